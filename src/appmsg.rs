@@ -0,0 +1,487 @@
+
+extern crate std;
+extern crate regex;
+
+use self::regex::Regex;
+use serde_json::Value;
+
+/// Content of an app message (MsgType 49), parsed from its `<appmsg>` xml.
+#[derive(Debug, Clone)]
+pub enum AppMsg {
+    /// AppMsgType 5, a shared link or a single article.
+    Link {
+        title: String,
+        des: String,
+        url: String,
+    },
+    /// an official account push with several articles.
+    Articles(Vec<Article>),
+    /// AppMsgType 6.
+    File { title: String, size: u64 },
+    /// AppMsgType 33 and 36.
+    MiniProgram {
+        title: String,
+        source: String,
+        url: String,
+    },
+    /// AppMsgType 2000.
+    Transfer { fee: String, memo: String },
+    /// AppMsgType 19, forwarded chat history.
+    ChatHistory { title: String, des: String },
+    Unknown {
+        app_msg_type: i64,
+        title: String,
+        url: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Article {
+    pub title: String,
+    pub digest: String,
+    pub url: String,
+}
+
+impl AppMsg {
+    /// parse an `AddMsgList` entry of MsgType 49.
+    pub fn from_json(msg: &Value) -> AppMsg {
+        let app_msg_type = msg["AppMsgType"].as_i64().unwrap_or(0);
+        let xml = content_xml(msg);
+
+        let title = tag(&xml, "title").unwrap_or_else(|| field(msg, "FileName"));
+        let des = tag(&xml, "des").unwrap_or_default();
+        let url = tag(&xml, "url").unwrap_or_else(|| field(msg, "Url"));
+
+        match app_msg_type {
+            5 => {
+                let articles = articles(&xml);
+                if articles.len() > 1 {
+                    AppMsg::Articles(articles)
+                } else {
+                    AppMsg::Link {
+                        title: title,
+                        des: des,
+                        url: url,
+                    }
+                }
+            }
+            6 => {
+                let size = tag(&xml, "totallen")
+                    .unwrap_or_else(|| field(msg, "FileSize"))
+                    .parse()
+                    .unwrap_or(0);
+
+                AppMsg::File {
+                    title: title,
+                    size: size,
+                }
+            }
+            19 => {
+                AppMsg::ChatHistory {
+                    title: title,
+                    des: des,
+                }
+            }
+            33 | 36 => {
+                AppMsg::MiniProgram {
+                    title: title,
+                    source: tag(&xml, "sourcedisplayname").unwrap_or_default(),
+                    url: url,
+                }
+            }
+            2000 => {
+                AppMsg::Transfer {
+                    fee: tag(&xml, "feedesc").unwrap_or_default(),
+                    memo: tag(&xml, "pay_memo").unwrap_or_default(),
+                }
+            }
+            _ => {
+                AppMsg::Unknown {
+                    app_msg_type: app_msg_type,
+                    title: title,
+                    url: url,
+                }
+            }
+        }
+    }
+
+    /// readable rendering for a conversation window.
+    pub fn to_html(&self) -> String {
+        match *self {
+            AppMsg::Link { ref title, ref des, ref url } => {
+                let mut html = format!("[Link] {}", link(title, url));
+                if !des.is_empty() {
+                    html.push_str(&format!("<br/>{}", escape(des)));
+                }
+                html
+            }
+            AppMsg::Articles(ref articles) => {
+                let mut html = "[Articles]".to_owned();
+                for article in articles {
+                    html.push_str(&format!("<br/>- {}", link(&article.title, &article.url)));
+                    if !article.digest.is_empty() {
+                        html.push_str(&format!(": {}", escape(&article.digest)));
+                    }
+                }
+                html
+            }
+            AppMsg::File { ref title, size } => {
+                format!("[File] {} ({})", escape(title), format_size(size))
+            }
+            AppMsg::MiniProgram { ref title, ref source, ref url } => {
+                if source.is_empty() {
+                    format!("[Mini Program] {}", link(title, url))
+                } else {
+                    format!("[Mini Program] {}: {}", escape(source), link(title, url))
+                }
+            }
+            AppMsg::Transfer { ref fee, ref memo } => {
+                let mut html = format!("[Transfer] {}", escape(fee));
+                if !memo.is_empty() {
+                    html.push_str(&format!(" {}", escape(memo)));
+                }
+                html.push_str("<br/>Please check it on your phone.");
+                html
+            }
+            AppMsg::ChatHistory { ref title, ref des } => {
+                format!("[Chat History] {}<br/>{}",
+                        escape(title),
+                        escape(des).replace('\n', "<br/>"))
+            }
+            AppMsg::Unknown { app_msg_type, ref title, ref url } => {
+                format!("[App Message {}] {}", app_msg_type, link(title, url))
+            }
+        }
+    }
+}
+
+/// a shared contact card, MsgType 42.
+#[derive(Debug, Clone)]
+pub struct Card {
+    pub user_name: String,
+    pub nick_name: String,
+    /// the wechat id, if the contact has set one.
+    pub alias: String,
+    pub region: String,
+}
+
+impl Card {
+    pub fn from_json(msg: &Value) -> Card {
+        let xml = content_xml(msg);
+        let info = &msg["RecommendInfo"];
+        let field = |name: &str, attr_name: &str| {
+            info[name]
+                .as_str()
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_owned())
+                .or_else(|| attr(&xml, "msg", attr_name))
+                .unwrap_or_default()
+        };
+
+        let province = field("Province", "province");
+        let city = field("City", "city");
+        let region = if city.is_empty() || city == province {
+            province
+        } else {
+            format!("{} {}", province, city)
+        };
+
+        Card {
+            user_name: field("UserName", "username"),
+            nick_name: field("NickName", "nickname"),
+            alias: attr(&xml, "msg", "alias").unwrap_or_default(),
+            region: region,
+        }
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut html = format!("[Contact Card] {}", escape(&self.nick_name));
+        if !self.alias.is_empty() {
+            html.push_str(&format!(" ({})", escape(&self.alias)));
+        }
+        if !self.region.is_empty() {
+            html.push_str(&format!("<br/>{}", escape(&self.region)));
+        }
+        if !self.user_name.is_empty() {
+            html.push_str(&format!("<br/>To add as a friend, use Add Buddy with {}",
+                                   escape(&self.user_name)));
+        }
+        html
+    }
+}
+
+/// a shared location, MsgType 48.
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// zoom level of the map.
+    pub scale: u32,
+    pub label: String,
+}
+
+impl Location {
+    pub fn from_json(msg: &Value) -> Option<Location> {
+        let xml = content_xml(msg);
+        let coordinate = |name: &str| attr(&xml, "location", name).and_then(|v| v.parse().ok());
+
+        let label = attr(&xml, "location", "poiname")
+            .or_else(|| attr(&xml, "location", "label"))
+            .unwrap_or_default();
+
+        Some(Location {
+                 latitude: coordinate("x")?,
+                 longitude: coordinate("y")?,
+                 scale: attr(&xml, "location", "scale").and_then(|v| v.parse().ok()).unwrap_or(15),
+                 label: label,
+             })
+    }
+
+    pub fn to_html(&self) -> String {
+        let osm = format!("https://www.openstreetmap.org/?mlat={0}&mlon={1}#map={2}/{0}/{1}",
+                          self.latitude,
+                          self.longitude,
+                          self.scale);
+        let geo = format!("geo:{},{}", self.latitude, self.longitude);
+
+        format!("[Location] {}<br/>{} {}",
+                escape(&self.label),
+                link("OpenStreetMap", &osm),
+                link(&geo, &geo))
+    }
+}
+
+/// xml in the `Content` of a message, without the sender of group messages.
+fn content_xml(msg: &Value) -> String {
+    let content = unescape(msg["Content"].as_str().unwrap_or(""));
+
+    // group messages start with the sender
+    let regex = Regex::new(r#"^@\w+:(?:<br/>)*"#).unwrap();
+    regex.replace(&content, "").into_owned()
+}
+
+/// every `<item>` of an official account push.
+fn articles(xml: &str) -> Vec<Article> {
+    let regex = Regex::new(r#"(?s)<item>(.*?)</item>"#).unwrap();
+
+    regex.captures_iter(xml)
+        .map(|caps| {
+            let item = caps.get(1).unwrap().as_str();
+            Article {
+                title: tag(item, "title").unwrap_or_default(),
+                digest: tag(item, "digest").unwrap_or_default(),
+                url: tag(item, "url").unwrap_or_default(),
+            }
+        })
+        .filter(|a| !a.title.is_empty())
+        .collect()
+}
+
+/// text of the first `<name>` element, without a CDATA wrapper.
+pub fn tag(xml: &str, name: &str) -> Option<String> {
+    let regex = Regex::new(&format!(r#"(?s)<{0}>(?:<!\[CDATA\[)?(.*?)(?:\]\]>)?</{0}>"#, name))
+        .unwrap();
+
+    regex.captures(xml)
+        .map(|caps| unescape(caps.get(1).unwrap().as_str().trim()))
+        .filter(|text| !text.is_empty())
+}
+
+/// value of the attribute `name` of the first `<element>`.
+pub fn attr(xml: &str, element: &str, name: &str) -> Option<String> {
+    let regex = Regex::new(&format!(r#"<{}\s[^>]*?\b{}\s*=\s*"([^"]*)""#, element, name))
+        .unwrap();
+
+    regex.captures(xml)
+        .map(|caps| unescape(caps.get(1).unwrap().as_str().trim()))
+        .filter(|value| !value.is_empty())
+}
+
+fn field(msg: &Value, name: &str) -> String {
+    msg[name].as_str().unwrap_or("").to_owned()
+}
+
+fn link(title: &str, url: &str) -> String {
+    if url.is_empty() {
+        escape(title)
+    } else {
+        format!(r#"<a href="{}">{}</a>"#, escape(url), escape(title))
+    }
+}
+
+fn format_size(size: u64) -> String {
+    match size {
+        0...1023 => format!("{} B", size),
+        1024...1048575 => format!("{:.1} KB", size as f64 / 1024.0),
+        _ => format!("{:.1} MB", size as f64 / 1048576.0),
+    }
+}
+
+pub fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app_msg(app_msg_type: i64, content: &str) -> Value {
+        json!({ "MsgType": 49, "AppMsgType": app_msg_type, "Content": content })
+    }
+
+    #[test]
+    fn shared_link() {
+        let msg = app_msg(5,
+                          "&lt;msg&gt;&lt;appmsg appid=\"\" sdkver=\"0\"&gt;\
+                           &lt;title&gt;Rust 1.0 &amp;amp; beyond&lt;/title&gt;\
+                           &lt;des&gt;release notes&lt;/des&gt;&lt;type&gt;5&lt;/type&gt;\
+                           &lt;url&gt;https://blog.rust-lang.org/?a=1&amp;amp;b=2&lt;/url&gt;\
+                           &lt;/appmsg&gt;&lt;/msg&gt;");
+
+        match AppMsg::from_json(&msg) {
+            AppMsg::Link { ref title, ref des, ref url } => {
+                assert_eq!(title, "Rust 1.0 & beyond");
+                assert_eq!(des, "release notes");
+                assert_eq!(url, "https://blog.rust-lang.org/?a=1&b=2");
+            }
+            m => panic!("expected Link, got {:?}", m),
+        }
+        assert_eq!(AppMsg::from_json(&msg).to_html(),
+                   "[Link] <a href=\"https://blog.rust-lang.org/?a=1&amp;b=2\">\
+                    Rust 1.0 &amp; beyond</a><br/>release notes");
+    }
+
+    #[test]
+    fn article_push() {
+        let msg = app_msg(5,
+                          "&lt;msg&gt;&lt;appmsg&gt;&lt;title&gt;&lt;![CDATA[first]]&gt;\
+                           &lt;/title&gt;&lt;type&gt;5&lt;/type&gt;&lt;mmreader&gt;\
+                           &lt;category count=\"2\"&gt;&lt;item&gt;\
+                           &lt;title&gt;&lt;![CDATA[first]]&gt;&lt;/title&gt;\
+                           &lt;url&gt;&lt;![CDATA[http://mp.weixin.qq.com/s?id=1]]&gt;&lt;/url&gt;\
+                           &lt;digest&gt;&lt;![CDATA[one]]&gt;&lt;/digest&gt;&lt;/item&gt;<br/>\
+                           &lt;item&gt;&lt;title&gt;&lt;![CDATA[second]]&gt;&lt;/title&gt;\
+                           &lt;url&gt;&lt;![CDATA[http://mp.weixin.qq.com/s?id=2]]&gt;&lt;/url&gt;\
+                           &lt;digest&gt;&lt;![CDATA[]]&gt;&lt;/digest&gt;&lt;/item&gt;\
+                           &lt;/category&gt;&lt;/mmreader&gt;&lt;/appmsg&gt;&lt;/msg&gt;");
+
+        match AppMsg::from_json(&msg) {
+            AppMsg::Articles(ref articles) => {
+                assert_eq!(articles.len(), 2);
+                assert_eq!(articles[0].title, "first");
+                assert_eq!(articles[0].digest, "one");
+                assert_eq!(articles[1].url, "http://mp.weixin.qq.com/s?id=2");
+                assert_eq!(articles[1].digest, "");
+            }
+            m => panic!("expected Articles, got {:?}", m),
+        }
+    }
+
+    #[test]
+    fn file_in_group() {
+        let msg = app_msg(6,
+                          "@abc:<br/>&lt;?xml version=\"1.0\"?&gt;<br/>&lt;msg&gt;\
+                           &lt;appmsg appid=\"wxeb7ec651dd0aefa9\" sdkver=\"0\"&gt;\
+                           &lt;title&gt;report.pdf&lt;/title&gt;&lt;type&gt;6&lt;/type&gt;\
+                           &lt;appattach&gt;&lt;totallen&gt;1536&lt;/totallen&gt;\
+                           &lt;fileext&gt;pdf&lt;/fileext&gt;&lt;/appattach&gt;\
+                           &lt;/appmsg&gt;&lt;/msg&gt;");
+
+        match AppMsg::from_json(&msg) {
+            AppMsg::File { ref title, size } => {
+                assert_eq!(title, "report.pdf");
+                assert_eq!(size, 1536);
+            }
+            m => panic!("expected File, got {:?}", m),
+        }
+        assert_eq!(AppMsg::from_json(&msg).to_html(), "[File] report.pdf (1.5 KB)");
+    }
+
+    #[test]
+    fn file_without_xml_uses_message_fields() {
+        let mut msg = app_msg(6, "");
+        msg["FileName"] = json!("a<b>.txt");
+        msg["FileSize"] = json!("12");
+
+        assert_eq!(AppMsg::from_json(&msg).to_html(), "[File] a&lt;b&gt;.txt (12 B)");
+    }
+
+    #[test]
+    fn transfer() {
+        let msg = app_msg(2000,
+                          "&lt;msg&gt;&lt;appmsg&gt;&lt;title&gt;&lt;![CDATA[微信转账]]&gt;\
+                           &lt;/title&gt;&lt;type&gt;2000&lt;/type&gt;&lt;wcpayinfo&gt;\
+                           &lt;feedesc&gt;&lt;![CDATA[￥8.88]]&gt;&lt;/feedesc&gt;\
+                           &lt;pay_memo&gt;&lt;![CDATA[lunch]]&gt;&lt;/pay_memo&gt;\
+                           &lt;/wcpayinfo&gt;&lt;/appmsg&gt;&lt;/msg&gt;");
+
+        assert_eq!(AppMsg::from_json(&msg).to_html(),
+                   "[Transfer] ￥8.88 lunch<br/>Please check it on your phone.");
+    }
+
+    #[test]
+    fn tag_and_attr() {
+        let xml = "<msg><title><![CDATA[ a <b> ]]></title><empty></empty>\
+                   <location x=\"1.5\" label=\"A &amp; B\" /></msg>";
+
+        assert_eq!(tag(xml, "title"), Some("a <b>".to_owned()));
+        assert_eq!(tag(xml, "empty"), None);
+        assert_eq!(tag(xml, "missing"), None);
+        assert_eq!(attr(xml, "location", "x"), Some("1.5".to_owned()));
+        assert_eq!(attr(xml, "location", "label"), Some("A & B".to_owned()));
+        assert_eq!(attr(xml, "location", "y"), None);
+    }
+
+    #[test]
+    fn contact_card() {
+        let msg = json!({
+            "MsgType": 42,
+            "Content": "&lt;?xml version=\"1.0\"?&gt;<br/>&lt;msg bigheadimgurl=\"\" \
+                        username=\"wxid_1\" nickname=\"Alice\" alias=\"alice01\" \
+                        province=\"Guangdong\" city=\"Shenzhen\" sex=\"2\" /&gt;<br/>",
+            "RecommendInfo": { "UserName": "@alice", "NickName": "", "Province": "广东",
+                               "City": "深圳" },
+        });
+
+        let card = Card::from_json(&msg);
+        assert_eq!(card.user_name, "@alice");
+        assert_eq!(card.nick_name, "Alice");
+        assert_eq!(card.alias, "alice01");
+        assert_eq!(card.region, "广东 深圳");
+        assert_eq!(card.to_html(),
+                   "[Contact Card] Alice (alice01)<br/>广东 深圳<br/>\
+                    To add as a friend, use Add Buddy with @alice");
+    }
+
+    #[test]
+    fn location() {
+        let msg = json!({
+            "MsgType": 48,
+            "Content": "@abc:<br/>&lt;?xml version=\"1.0\"?&gt;<br/>&lt;msg&gt;<br/>\
+                        \t&lt;location x=\"22.543099\" y=\"114.057868\" scale=\"16\" \
+                        label=\"深圳市福田区\" maptype=\"0\" poiname=\"市民中心\" /&gt;\
+                        <br/>&lt;/msg&gt;<br/>",
+        });
+
+        let location = Location::from_json(&msg).unwrap();
+        assert_eq!(location.latitude, 22.543099);
+        assert_eq!(location.longitude, 114.057868);
+        assert_eq!(location.scale, 16);
+        assert_eq!(location.label, "市民中心");
+        assert!(location.to_html().contains("geo:22.543099,114.057868"));
+
+        let broken = json!({ "Content": "&lt;msg&gt;&lt;location x=\"north\" /&gt;" });
+        assert!(Location::from_json(&broken).is_none());
+    }
+}
@@ -1,28 +1,20 @@
 
 extern crate std;
 
-use std::os::raw::c_void;
 use std::ffi::CString;
 use std::cmp::*;
-use std::sync::atomic;
-use std::sync::atomic::AtomicUsize;
 
-use pointer::Pointer;
 use serde_json::Value;
 
-lazy_static!{
-    static ref TOKEN: AtomicUsize = AtomicUsize::new(1);
-}
-
 #[derive(Clone, Debug)]
 pub struct ChatRoom {
     id: String,
     alias: String,
+    head_img_url: String,
 
     members: Vec<MemberUser>,
 
     token: usize,
-    ptr: Pointer,
 }
 
 #[derive(Clone, Debug)]
@@ -35,9 +27,9 @@ pub struct MemberUser {
 impl MemberUser {
     fn from_json(json: &Value) -> MemberUser {
         MemberUser {
-            user_name: json["UserName"].as_str().unwrap().to_owned(),
-            nick_name: json["NickName"].as_str().unwrap().to_owned(),
-            display_name: json["DisplayName"].as_str().unwrap().to_owned(),
+            user_name: json["UserName"].as_str().unwrap_or("").to_owned(),
+            nick_name: json["NickName"].as_str().unwrap_or("").to_owned(),
+            display_name: json["DisplayName"].as_str().unwrap_or("").to_owned(),
         }
     }
 
@@ -47,25 +39,55 @@ impl MemberUser {
 }
 
 impl ChatRoom {
-    pub fn from_json(json: &Value) -> ChatRoom {
+    /// `token` identifies the chat within its connection.
+    pub fn from_json(json: &Value, token: usize) -> ChatRoom {
 
         let mut cr = ChatRoom {
-            id: json["UserName"].as_str().unwrap().to_owned(),
-            alias: json["NickName"].as_str().unwrap().to_owned(),
+            id: json["UserName"].as_str().unwrap_or("").to_owned(),
+            alias: json["NickName"].as_str().unwrap_or("").to_owned(),
+            head_img_url: json["HeadImgUrl"].as_str().unwrap_or("").to_owned(),
             members: vec![],
 
-            token: TOKEN.fetch_add(1, atomic::Ordering::SeqCst),
-            ptr: Pointer::new(),
+            token: token,
         };
 
-        let members = json["MemberList"].as_array().unwrap();
-        for member in members {
-            cr.members.push(MemberUser::from_json(member));
+        if let Some(members) = json["MemberList"].as_array() {
+            cr.members = members.iter().map(MemberUser::from_json).collect();
         }
 
         cr
     }
 
+    /// apply a modified contact entry of this chat, returns the members who
+    /// joined and who left.
+    pub fn update(&mut self, json: &Value) -> (Vec<MemberUser>, Vec<MemberUser>) {
+        if let Some(alias) = json["NickName"].as_str() {
+            self.alias = alias.to_owned();
+        }
+        if let Some(url) = json["HeadImgUrl"].as_str() {
+            self.head_img_url = url.to_owned();
+        }
+
+        // an entry without members only changes the name
+        let members: Vec<MemberUser> = match json["MemberList"].as_array() {
+            Some(list) if !list.is_empty() => list.iter().map(MemberUser::from_json).collect(),
+            _ => return (vec![], vec![]),
+        };
+
+        let joined = members.iter()
+            .filter(|m| !self.members.iter().any(|o| o.user_name == m.user_name))
+            .cloned()
+            .collect();
+        let left = self.members
+            .iter()
+            .filter(|o| !members.iter().any(|m| m.user_name == o.user_name))
+            .cloned()
+            .collect();
+        self.members = members;
+
+        (joined, left)
+    }
+
     pub fn members(&self) -> &Vec<MemberUser> {
         &self.members
     }
@@ -90,12 +112,8 @@ impl ChatRoom {
         self.id.clone()
     }
 
-    pub fn set_chat_ptr(&mut self, chat: *mut c_void) {
-        self.ptr.set(chat);
-    }
-
-    pub fn chat_ptr(&self) -> *mut c_void {
-        self.ptr.as_ptr()
+    pub fn head_img_url(&self) -> String {
+        self.head_img_url.clone()
     }
 }
 
@@ -0,0 +1,2139 @@
+
+extern crate std;
+extern crate hyper;
+extern crate regex;
+extern crate time;
+
+use self::hyper::header::{Cookie, Headers};
+use self::regex::Regex;
+use serde_json;
+use serde_json::Value;
+use serde_json::Map;
+use std::cmp;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::io::Write;
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+use user::User;
+use appmsg;
+use chatroom::{ChatRoom, MemberUser};
+use transport;
+use transport::{Transport, Endpoints, raw_headers};
+
+#[derive(Debug)]
+pub enum Error {
+    Transport(transport::Error),
+    Json(serde_json::Error),
+    Protocol(String),
+    /// nothing sent to this contact can be revoked any more.
+    NotRevocable(&'static str),
+    Cancelled,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Transport(ref e) => write!(f, "{}", e),
+            Error::Json(ref e) => write!(f, "json error: {}", e),
+            Error::Protocol(ref e) => write!(f, "protocol error: {}", e),
+            Error::NotRevocable(e) => write!(f, "{}", e),
+            Error::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl From<transport::Error> for Error {
+    fn from(e: transport::Error) -> Error {
+        Error::Transport(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// how many times an expired qr-code is refreshed before giving up.
+const MAX_QR_REFRESH: usize = 5;
+/// how many failed `login` polls in a row are tolerated.
+const MAX_POLL_RETRY: usize = 3;
+/// how many failed sync rounds in a row are retried before disconnecting.
+const MAX_SYNC_RETRY: u32 = 6;
+/// upper bound of the delay between two sync retries.
+const MAX_BACKOFF_SECS: u64 = 32;
+/// size of one `webwxuploadmedia` request.
+const UPLOAD_CHUNK_SIZE: usize = 512 * 1024;
+/// size of one ranged request of a download.
+const DOWNLOAD_CHUNK_SIZE: usize = 512 * 1024;
+/// how many delivered message ids are remembered to drop duplicates.
+const MAX_SEEN_MSGS: usize = 1000;
+/// how many of them are saved with the session, a resumed sync only repeats
+/// the last few messages.
+const MAX_SAVED_SEEN_MSGS: usize = 50;
+/// how long after sending a message it can still be revoked.
+const REVOKE_WINDOW_SECS: i64 = 120;
+
+/// Everything the client reports back to its user, in the order it happened.
+#[derive(Debug)]
+pub enum Event {
+    /// a new login qr-code image is ready to be shown.
+    QrCode(Vec<u8>),
+    /// the qr-code was scanned, waiting for the user to confirm on the phone.
+    /// carries the avatar image of the scanning user if the server sent one.
+    Scanned(Option<Vec<u8>>),
+    /// login confirmed on the phone, the login dialog can be closed.
+    Confirmed,
+    /// no login could be established, nothing else will happen.
+    LoginFailed(LoginError),
+    /// login finished, carries the user name of the current user.
+    LoggedIn(String),
+    /// the session credentials changed, persist them to skip the qr-code
+    /// next time, see `Client::restore_session`.
+    SessionUpdated(Value),
+    ContactAdded(User),
+    /// a known contact changed, e.g. was renamed.
+    ContactUpdated(User),
+    /// user name of a contact that was deleted.
+    ContactRemoved(String),
+    GroupAdded(ChatRoom),
+    /// a known group changed, with the members who joined and left.
+    GroupUpdated(ChatRoom, Vec<MemberUser>, Vec<MemberUser>),
+    GroupRemoved(ChatRoom),
+    /// a new message.
+    Message(Message),
+    /// the session is over, nothing else will happen.
+    Disconnected(Disconnect),
+}
+
+/// Outcome of one `synccheck`, from its `retcode` and `selector`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncCheckResult {
+    /// nothing changed.
+    Idle,
+    /// selector 2, new messages.
+    NewMessage,
+    /// selector 4, a contact or the own profile was modified.
+    ContactChanged,
+    /// selector 6, contact list or red packet changes.
+    ContactListChanged,
+    /// selector 7, a chat was entered or read on the phone.
+    PhoneActivity,
+    /// any other selector, fetched like new messages.
+    Unknown(isize),
+    /// retcode 1100, logged out from the phone.
+    LoggedOut,
+    /// retcode 1101, logged in on another device.
+    LoggedInElsewhere,
+    /// retcode 1102, the server rejected the session.
+    SessionInvalid,
+    /// any other non-zero retcode.
+    Failed(isize),
+}
+
+impl SyncCheckResult {
+    pub fn from_codes(retcode: isize, selector: isize) -> SyncCheckResult {
+        match (retcode, selector) {
+            (0, 0) => SyncCheckResult::Idle,
+            (0, 2) => SyncCheckResult::NewMessage,
+            (0, 4) => SyncCheckResult::ContactChanged,
+            (0, 6) => SyncCheckResult::ContactListChanged,
+            (0, 7) => SyncCheckResult::PhoneActivity,
+            (0, selector) => SyncCheckResult::Unknown(selector),
+            (1100, _) => SyncCheckResult::LoggedOut,
+            (1101, _) => SyncCheckResult::LoggedInElsewhere,
+            (1102, _) => SyncCheckResult::SessionInvalid,
+            (retcode, _) => SyncCheckResult::Failed(retcode),
+        }
+    }
+
+    /// message shown to the user if the session could not be resumed, `None`
+    /// if this result does not end the session.
+    pub fn end_reason(&self) -> Option<&'static str> {
+        match *self {
+            SyncCheckResult::LoggedOut => Some("You have logged out on your phone."),
+            SyncCheckResult::LoggedInElsewhere => {
+                Some("You are already logged in other devices.\nplease login again.")
+            }
+            SyncCheckResult::SessionInvalid => {
+                Some("The session is no longer valid.\nplease login again.")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Why a running session ended.
+#[derive(Debug)]
+pub enum Disconnect {
+    /// the network or the server kept failing, worth trying again later.
+    Network(String),
+    /// the server invalidated the session and it could not be resumed.
+    SessionExpired(String),
+    /// `Client::cancel` was called.
+    Cancelled,
+}
+
+/// Why no login could be established.
+#[derive(Debug)]
+pub enum LoginError {
+    /// the server could not be reached, worth trying again later.
+    Network(String),
+    /// the server rejected the login, or it was never confirmed.
+    Rejected(String),
+}
+
+impl LoginError {
+    fn from_error(e: &Error) -> LoginError {
+        match *e {
+            Error::Transport(_) => LoginError::Network(format!("{}", e)),
+            _ => LoginError::Rejected(format!("{}", e)),
+        }
+    }
+}
+
+/// Result of one poll of the `login` endpoint.
+#[derive(Debug, PartialEq)]
+pub enum ScanStatus {
+    /// the long poll timed out without anything happening (408).
+    Waiting,
+    /// scanned but not yet confirmed, with the avatar of the scanning user (201).
+    Scanned(Option<Vec<u8>>),
+    /// confirmed, carries the redirect uri to finish the login with (200).
+    Confirmed(String),
+    /// the qr-code is no longer valid and must be refreshed (400).
+    Expired,
+}
+
+struct Session {
+    uin: String,
+    sid: String,
+    skey: String,
+    device_id: String,
+    pass_ticket: String,
+    headers: Headers,
+    user_info: Value,
+    /// `SyncKey`, sent to `webwxsync`.
+    sync_keys: Value,
+    /// `SyncCheckKey`, sent to `synccheck`.
+    sync_check_keys: Value,
+    /// ids of the latest delivered messages, oldest first.
+    seen_msgs: VecDeque<String>,
+}
+
+unsafe impl std::marker::Sync for Session {}
+
+impl Session {
+    fn new(endpoints: &Endpoints) -> Session {
+        let mut headers = Headers::new();
+        headers.set_raw("Cookie", vec![vec![]]);
+        headers.set_raw("ContentType",
+                        vec![b"application/json; charset=UTF-8".to_vec()]);
+        headers.set_raw("Referer", vec![endpoints.web_referer().into_bytes()]);
+        headers.set_raw("Accept",
+                        vec![b"application/json, text/plain, */*".to_vec()]);
+
+        Session {
+            uin: String::new(),
+            sid: String::new(),
+            skey: String::new(),
+            device_id: format!("e56{}", time_stamp()),
+            pass_ticket: String::new(),
+            headers: headers,
+            user_info: Value::Null,
+            sync_keys: Value::Null,
+            sync_check_keys: Value::Null,
+            seen_msgs: VecDeque::new(),
+        }
+    }
+
+    fn sync_check_key_str(&self) -> String {
+
+        // an old saved session only has the sync key
+        let keys = if self.sync_check_keys.is_array() {
+            &self.sync_check_keys
+        } else {
+            &self.sync_keys
+        };
+        assert!(keys.is_array());
+
+        let mut buf = String::new();
+        for item in keys.as_array().unwrap() {
+            let k = item["Key"].as_i64().unwrap();
+            let v = item["Val"].as_i64().unwrap();
+
+            buf.push_str(&format!("{}_{}|", k, v));
+        }
+        buf.pop();
+
+        buf
+    }
+
+    fn sync_key(&self) -> Value {
+        assert!(self.sync_keys.is_array());
+
+        let count = self.sync_keys.as_array().unwrap().len();
+        let value = json!({"Count" : count, "List" : self.sync_keys});
+
+        value
+    }
+
+    fn set_sync_key(&mut self, json: &Value) {
+        if let Value::Array(ref list) = json["List"] {
+            self.sync_keys = Value::Array(list.clone());
+        }
+    }
+
+    fn set_sync_check_key(&mut self, json: &Value) {
+        if let Value::Array(ref list) = json["List"] {
+            self.sync_check_keys = Value::Array(list.clone());
+        }
+    }
+
+    /// remember `msg_id`, returns false if it was delivered before.
+    fn mark_seen(&mut self, msg_id: &str) -> bool {
+        if self.seen_msgs.iter().any(|id| id == msg_id) {
+            return false;
+        }
+
+        if self.seen_msgs.len() >= MAX_SEEN_MSGS {
+            self.seen_msgs.pop_front();
+        }
+        self.seen_msgs.push_back(msg_id.to_owned());
+
+        true
+    }
+
+    fn set_user_info(&mut self, json: &Value) {
+        self.user_info = json["User"].clone()
+    }
+
+    fn user_name(&self) -> &str {
+        self.user_info["UserName"].as_str().unwrap_or("")
+    }
+
+    fn nick_name(&self) -> &str {
+        self.user_info["NickName"].as_str().unwrap_or("")
+    }
+
+    fn head_img_url(&self) -> &str {
+        self.user_info["HeadImgUrl"].as_str().unwrap_or("")
+    }
+
+    fn cookies(&self) -> Vec<String> {
+        match self.headers.get::<Cookie>() {
+            Some(jar) => jar.iter().filter(|c| !c.is_empty()).cloned().collect(),
+            None => vec![],
+        }
+    }
+
+    fn set_cookies(&mut self, cookies: &[String]) {
+        println!("cookies: {:?}", cookies);
+        let ref mut jar = self.headers.get_mut::<Cookie>().unwrap();
+        jar.retain(|c| !c.is_empty());
+        for c in cookies.iter() {
+            let i = c.split(';').next().unwrap().trim();
+            if i.is_empty() {
+                continue;
+            }
+
+            // a new value replaces the old cookie of the same name
+            let name = i.split('=').next().unwrap().to_owned();
+            jar.retain(|c| c.split('=').next() != Some(&name));
+            jar.push(i.to_owned());
+        }
+    }
+
+    fn has_credentials(&self) -> bool {
+        !self.uin.is_empty() && !self.sid.is_empty()
+    }
+
+    fn to_json(&self) -> Value {
+        let skip = self.seen_msgs.len().saturating_sub(MAX_SAVED_SEEN_MSGS);
+        let seen_msgs: Vec<&String> = self.seen_msgs.iter().skip(skip).collect();
+
+        json!({
+            "Uin": self.uin,
+            "Sid": self.sid,
+            "Skey": self.skey,
+            "DeviceID": self.device_id,
+            "PassTicket": self.pass_ticket,
+            "Cookies": self.cookies(),
+            "SyncKey": self.sync_keys,
+            "SyncCheckKey": self.sync_check_keys,
+            "SeenMsgIds": seen_msgs,
+        })
+    }
+
+    fn restore(&mut self, json: &Value) {
+        let field = |name: &str| json[name].as_str().unwrap_or("").to_owned();
+
+        self.uin = field("Uin");
+        self.sid = field("Sid");
+        self.skey = field("Skey");
+        self.pass_ticket = field("PassTicket");
+        if !field("DeviceID").is_empty() {
+            self.device_id = field("DeviceID");
+        }
+        if json["SyncKey"].is_array() {
+            self.sync_keys = json["SyncKey"].clone();
+        }
+        if json["SyncCheckKey"].is_array() {
+            self.sync_check_keys = json["SyncCheckKey"].clone();
+        }
+        if let Value::Array(ref ids) = json["SeenMsgIds"] {
+            self.seen_msgs = ids.iter()
+                .filter_map(|id| id.as_str())
+                .map(|id| id.to_owned())
+                .collect();
+        }
+        if let Value::Array(ref cookies) = json["Cookies"] {
+            let cookies: Vec<String> = cookies
+                .iter()
+                .filter_map(|c| c.as_str())
+                .map(|c| c.to_owned())
+                .collect();
+            self.set_cookies(&cookies);
+        }
+    }
+
+    fn base_data(&self) -> Value {
+
+        let mut base_obj = Map::with_capacity(4);
+        base_obj.insert("Uin".to_owned(),
+                        json!(self.uin.parse::<usize>().unwrap_or(0)));
+        base_obj.insert("Sid".to_owned(), Value::String(self.sid.clone()));
+        base_obj.insert("Skey".to_owned(), Value::String(self.skey.clone()));
+        base_obj.insert("DeviceID".to_owned(), Value::String(self.device_id.clone()));
+
+        let mut obj = Map::new();
+        obj.insert("BaseRequest".to_owned(), Value::Object(base_obj));
+
+        Value::Object(obj)
+    }
+
+    fn status_notify_data(&self) -> Value {
+
+        let mut value = self.base_data();
+
+        value["Code"] = json!(3);
+        value["FromUserName"] = json!(self.user_name());
+        value["ToUserName"] = json!(self.user_name());
+        value["ClientMsgId"] = json!(time_stamp());
+
+        value
+    }
+
+    fn group_info_data(&self, groups: &[String]) -> Value {
+
+        let mut list = vec![];
+        for id in groups {
+            let item = json!({ "UserName": json!(id),
+                               "ChatRoomId": json!("") });
+            list.push(item);
+        }
+
+        let mut value = self.base_data();
+        value["Count"] = json!(groups.len());
+        value["List"] = json!(list);
+
+        value
+    }
+
+    fn message_check_data(&self) -> Value {
+
+        let mut value = self.base_data();
+
+        value["SyncKey"] = self.sync_key().clone();
+        value["rr"] = json!(!time_stamp());
+
+        value
+    }
+
+    fn message_send_data(&self, who: &str, content: &str) -> Value {
+
+        let mut id = time_stamp().to_string();
+        id.push_str("1234");
+
+        let msg = json!({
+            "Type" : 1,
+            "Content" : json!(content),
+            "FromUserName" : json!(self.user_name()),
+            "ToUserName" : json!(who),
+            "LocalID" : json!(id),
+            "ClientMsgId" : json!(id)
+        });
+
+        let mut value = self.base_data();
+        value["Msg"] = msg;
+        value["Scene"] = json!(0);
+
+        value
+    }
+
+    fn image_send_data(&self, who: &str, media_id: &str) -> Value {
+
+        let mut id = time_stamp().to_string();
+        id.push_str("1234");
+
+        let msg = json!({
+            "Type" : 3,
+            "Content" : "",
+            "MediaId" : json!(media_id),
+            "FromUserName" : json!(self.user_name()),
+            "ToUserName" : json!(who),
+            "LocalID" : json!(id),
+            "ClientMsgId" : json!(id)
+        });
+
+        let mut value = self.base_data();
+        value["Msg"] = msg;
+        value["Scene"] = json!(0);
+
+        value
+    }
+
+    fn verify_user_data(&self,
+                        opcode: i64,
+                        user_name: &str,
+                        ticket: &str,
+                        content: &str)
+                        -> Value {
+
+        let mut value = self.base_data();
+        value["Opcode"] = json!(opcode);
+        value["VerifyUserListSize"] = json!(1);
+        value["VerifyUserList"] = json!([{
+            "Value": user_name,
+            "VerifyUserTicket": ticket,
+        }]);
+        value["VerifyContent"] = json!(content);
+        value["SceneListCount"] = json!(1);
+        value["SceneList"] = json!([33]);
+        value["skey"] = json!(self.skey);
+
+        value
+    }
+
+    fn revoke_data(&self, sent: &SentMessage) -> Value {
+
+        let mut value = self.base_data();
+        value["ClientMsgId"] = json!(sent.local_id);
+        value["SvrMsgId"] = json!(sent.msg_id);
+        value["ToUserName"] = json!(sent.to);
+
+        value
+    }
+
+    fn upload_media_data(&self, who: &str, size: usize) -> Value {
+
+        let mut value = self.base_data();
+        value["UploadType"] = json!(2);
+        value["ClientMediaId"] = json!(time_stamp());
+        value["TotalLen"] = json!(size);
+        value["StartPos"] = json!(0);
+        value["DataLen"] = json!(size);
+        value["MediaType"] = json!(4);
+        value["FromUserName"] = json!(self.user_name());
+        value["ToUserName"] = json!(who);
+        value["FileMd5"] = json!("");
+
+        value
+    }
+
+    fn file_send_data(&self, who: &str, file_name: &str, size: usize, media_id: &str) -> Value {
+
+        let mut id = time_stamp().to_string();
+        id.push_str("1234");
+
+        let ext = file_name.rsplit('.').next().unwrap_or("");
+        let content = format!("<appmsg appid='wxeb7ec651dd0aefa9' sdkver=''><title>{}</title>\
+                               <des></des><action></action><type>6</type><content></content>\
+                               <url></url><lowurl></lowurl><appattach><totallen>{}</totallen>\
+                               <attachid>{}</attachid><fileext>{}</fileext></appattach>\
+                               <extinfo></extinfo></appmsg>",
+                              appmsg::escape(file_name),
+                              size,
+                              media_id,
+                              appmsg::escape(ext));
+
+        let msg = json!({
+            "Type" : 6,
+            "Content" : json!(content),
+            "FromUserName" : json!(self.user_name()),
+            "ToUserName" : json!(who),
+            "LocalID" : json!(id),
+            "ClientMsgId" : json!(id)
+        });
+
+        let mut value = self.base_data();
+        value["Msg"] = msg;
+        value["Scene"] = json!(0);
+
+        value
+    }
+
+    /// value of the `webwx_data_ticket` cookie, required by uploads.
+    fn data_ticket(&self) -> String {
+        self.cookies()
+            .iter()
+            .filter_map(|c| {
+                let mut kv = c.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some("webwx_data_ticket"), Some(v)) => Some(v.to_owned()),
+                    _ => None,
+                }
+            })
+            .next()
+            .unwrap_or_default()
+    }
+}
+
+/// a message sent by us, as acknowledged by `webwxsendmsg`.
+#[derive(Debug, Clone)]
+pub struct SentMessage {
+    pub msg_id: String,
+    pub local_id: String,
+    pub to: String,
+    /// seconds since epoch.
+    pub time: i64,
+}
+
+impl SentMessage {
+    fn from_json(json: &Value, to: &str) -> Option<SentMessage> {
+        let msg_id = json["MsgID"].as_str().unwrap_or("");
+        let local_id = json["LocalID"].as_str().unwrap_or("");
+
+        if msg_id.is_empty() {
+            return None;
+        }
+
+        Some(SentMessage {
+                 msg_id: msg_id.to_owned(),
+                 local_id: local_id.to_owned(),
+                 to: to.to_owned(),
+                 time: time::get_time().sec,
+             })
+    }
+}
+
+/// a received message, one entry of `AddMsgList`.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub id: String,
+    pub from: String,
+    pub to: String,
+    /// the member who wrote a group message, empty otherwise.
+    pub sender: String,
+    /// seconds since epoch.
+    pub time: i64,
+    /// `MsgType`, e.g. 1 text, 3 image, 34 voice, 49 app message.
+    pub msg_type: i64,
+    /// `Content` without the sender of group messages, xml for most types
+    /// other than text.
+    pub content: String,
+    /// the whole entry, for the fields of specific message types.
+    pub raw: Value,
+}
+
+impl Message {
+    pub fn from_json(json: &Value) -> Message {
+        let field = |name: &str| json[name].as_str().unwrap_or("").to_owned();
+
+        let from = field("FromUserName");
+        let content = field("Content");
+        let regex = Regex::new(r#"(?s)^(@\w+):(?:<br/>)*(.*)$"#).unwrap();
+        let (sender, content) = match regex.captures(&content) {
+            Some(ref caps) if from.starts_with("@@") => {
+                (caps[1].to_owned(), caps[2].to_owned())
+            }
+            _ => (String::new(), content.clone()),
+        };
+
+        Message {
+            id: field("MsgId"),
+            from: from,
+            to: field("ToUserName"),
+            sender: sender,
+            time: json["CreateTime"].as_i64().unwrap_or(0),
+            msg_type: json["MsgType"].as_i64().unwrap_or(0),
+            content: content,
+            raw: json.clone(),
+        }
+    }
+}
+
+/// a friend request, the `RecommendInfo` of a MsgType 37 message.
+#[derive(Debug, Clone)]
+pub struct FriendRequest {
+    pub user_name: String,
+    pub nick_name: String,
+    /// the greeting sent with the request.
+    pub content: String,
+    pub ticket: String,
+}
+
+impl FriendRequest {
+    pub fn from_json(msg: &Value) -> Option<FriendRequest> {
+        let info = &msg["RecommendInfo"];
+        let field = |name: &str| info[name].as_str().unwrap_or("").to_owned();
+
+        if field("UserName").is_empty() {
+            return None;
+        }
+
+        Some(FriendRequest {
+                 user_name: field("UserName"),
+                 nick_name: field("NickName"),
+                 content: field("Content"),
+                 ticket: field("Ticket"),
+             })
+    }
+}
+
+/// A web wechat client without any libpurple dependency. All calls block,
+/// progress is reported through the `Event` channel given to `new`.
+pub struct Client {
+    transport: Box<Transport>,
+    endpoints: Endpoints,
+    session: RwLock<Session>,
+    events: Mutex<Sender<Event>>,
+    cancelled: AtomicBool,
+    chat_token: AtomicUsize,
+    /// the last message sent to each contact, for revoking it.
+    last_sent: Mutex<HashMap<String, SentMessage>>,
+
+    user_list: RwLock<BTreeSet<User>>,
+    chat_list: RwLock<BTreeSet<ChatRoom>>,
+}
+
+impl Client {
+    pub fn new(transport: Box<Transport>, endpoints: Endpoints, events: Sender<Event>) -> Client {
+        Client {
+            transport: transport,
+            session: RwLock::new(Session::new(&endpoints)),
+            endpoints: endpoints,
+            events: Mutex::new(events),
+            cancelled: AtomicBool::new(false),
+            chat_token: AtomicUsize::new(1),
+            last_sent: Mutex::new(HashMap::new()),
+
+            user_list: RwLock::new(BTreeSet::new()),
+            chat_list: RwLock::new(BTreeSet::new()),
+        }
+    }
+
+    pub fn endpoints(&self) -> &Endpoints {
+        &self.endpoints
+    }
+
+    /// stop polling the server, `run` returns once the request in flight
+    /// is done, without emitting anything else.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn check_cancelled(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+
+        Ok(())
+    }
+
+    fn emit(&self, event: Event) {
+        // nobody listening any more, nothing to do
+        let events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = events.send(event);
+    }
+
+    /// run the whole session: login, fetch contacts and receive messages
+    /// until the server logs us out.
+    pub fn run(&self) {
+        // a bug must not leave the account looking online
+        if panic::catch_unwind(AssertUnwindSafe(|| self.run_session())).is_err() {
+            self.emit(Event::Disconnected(Disconnect::Network("internal error".to_owned())));
+        }
+    }
+
+    fn run_session(&self) {
+        if let Err(e) = self.establish_session() {
+            println!("login failed: {}", e);
+            if !self.is_cancelled() {
+                self.emit(Event::LoginFailed(LoginError::from_error(&e)));
+            }
+            return;
+        }
+
+        let reason = match self.start_session() {
+            Ok(()) => self.sync_loop(),
+            Err(e) => Disconnect::Network(format!("{}", e)),
+        };
+
+        println!("disconnected: {:?}", reason);
+        if !self.is_cancelled() {
+            self.emit(Event::Disconnected(reason));
+        }
+    }
+
+    /// resume the restored session if possible, only show a qr-code when
+    /// that is rejected.
+    fn establish_session(&self) -> Result<()> {
+        if self.session.read().unwrap().has_credentials() {
+            match self.resume_session() {
+                Ok(()) => return Ok(()),
+                // not rejected, the session may still be good once the
+                // server is reachable again
+                Err(e @ Error::Transport(_)) => return Err(e),
+                Err(e) => println!("could not resume session: {}", e),
+            }
+        }
+
+        let redirect_uri = self.wait_for_login()?;
+        self.login(&redirect_uri)
+    }
+
+    /// get the current credentials working again without a qr-code: reuse
+    /// them as they are, or ask the phone to confirm a push login.
+    fn resume_session(&self) -> Result<()> {
+        match self.init() {
+            Ok(()) => return Ok(()),
+            Err(e @ Error::Transport(_)) => return Err(e),
+            Err(e) => println!("stored session rejected: {}", e),
+        }
+
+        match self.push_login()? {
+            Some(redirect_uri) => {
+                self.login(&redirect_uri)?;
+                self.emit(Event::SessionUpdated(self.session()));
+
+                Ok(())
+            }
+            None => Err(Error::Protocol("push login was not confirmed".to_owned())),
+        }
+    }
+
+    fn start_session(&self) -> Result<()> {
+        let user_name = self.user_name();
+        self.emit(Event::LoggedIn(user_name));
+        self.emit(Event::SessionUpdated(self.session()));
+
+        self.status_notify()?;
+        self.fetch_contact()
+    }
+
+    /// credentials of the current session, to be given to `restore_session`
+    /// by the next client.
+    pub fn session(&self) -> Value {
+        self.session.read().unwrap().to_json()
+    }
+
+    /// reuse the credentials saved from `session` instead of a new qr-code login.
+    pub fn restore_session(&self, json: &Value) {
+        self.session.write().unwrap().restore(json);
+    }
+
+    /// ask the phone of the stored `uin` to confirm a login without scanning,
+    /// returns the login redirect uri, or `None` if it was not confirmed.
+    pub fn push_login(&self) -> Result<Option<String>> {
+        let url = {
+            let session = self.session.read().unwrap();
+            self.endpoints
+                .web_url(&format!("/cgi-bin/mmwebwx-bin/webwxpushloginurl?uin={}",
+                                  session.uin))
+        };
+
+        let json = self.get(&url)?.parse::<Value>()?;
+        let uuid = match (json["ret"].as_str(), json["uuid"].as_str()) {
+            (Some("0"), Some(uuid)) => uuid.to_owned(),
+            _ => return Ok(None),
+        };
+
+        self.emit(Event::Scanned(None));
+        self.wait_for_scan(&uuid, true)
+    }
+
+    /// show qr-codes until one is confirmed, refreshing expired ones,
+    /// returns the login redirect uri.
+    pub fn wait_for_login(&self) -> Result<String> {
+        for _ in 0..MAX_QR_REFRESH {
+            let uuid = self.get_uuid()?;
+            let qrcode = self.get_qrcode(&uuid)?;
+            self.emit(Event::QrCode(qrcode));
+
+            if let Some(uri) = self.wait_for_scan(&uuid, false)? {
+                return Ok(uri);
+            }
+
+            println!("qr-code {} expired, refreshing", uuid);
+        }
+
+        Err(Error::Protocol("qr-code expired, please login again.".to_owned()))
+    }
+
+    pub fn get_uuid(&self) -> Result<String> {
+        let redirect = self.endpoints.web_url("/cgi-bin/mmwebwx-bin/webwxnewloginpage");
+        let url = self.endpoints
+            .login_url(&format!("/jslogin?appid=wx782c26e4c19acffb&redirect_uri={}\
+                                 &fun=new&lang=zh_CN",
+                                redirect));
+        let result = self.get(&url)?;
+
+        regex_cap(&result, r#"uuid\s*=\s*"([-\w=]+)""#).map(|s| s.to_owned())
+    }
+
+    pub fn get_qrcode(&self, uuid: &str) -> Result<Vec<u8>> {
+        let url = self.endpoints.login_url(&format!("/qrcode/{}", uuid));
+
+        self.get_bytes(&url)
+    }
+
+    /// poll until the qr-code for `uuid` is confirmed, returns the login
+    /// redirect uri, or `None` if the qr-code expired first. `scanned` skips
+    /// straight to waiting for the confirmation, as for a push login.
+    pub fn wait_for_scan(&self, uuid: &str, scanned: bool) -> Result<Option<String>> {
+        // tip=1 until scanned, tip=0 while waiting for the confirmation
+        let mut tip = if scanned { 0 } else { 1 };
+        let mut failures = 0;
+
+        loop {
+            self.check_cancelled()?;
+
+            match self.check_scan(uuid, tip) {
+                Ok(ScanStatus::Waiting) => {}
+                Ok(ScanStatus::Scanned(avatar)) => {
+                    if tip == 1 {
+                        tip = 0;
+                        self.emit(Event::Scanned(avatar));
+                    }
+                }
+                Ok(ScanStatus::Confirmed(uri)) => {
+                    self.emit(Event::Confirmed);
+                    return Ok(Some(uri));
+                }
+                Ok(ScanStatus::Expired) => return Ok(None),
+                Err(e) => {
+                    failures += 1;
+                    println!("check scan failed ({}/{}): {}", failures, MAX_POLL_RETRY, e);
+                    if failures >= MAX_POLL_RETRY {
+                        return Err(e);
+                    }
+
+                    thread::sleep(Duration::from_secs(2));
+                    continue;
+                }
+            }
+
+            failures = 0;
+        }
+    }
+
+    /// one poll of the `login` endpoint.
+    pub fn check_scan(&self, uuid: &str, tip: usize) -> Result<ScanStatus> {
+        let url = self.endpoints
+            .login_url(&format!("/cgi-bin/mmwebwx-bin/login?uuid={}&tip={}&_={}",
+                                uuid,
+                                tip,
+                                time_stamp()));
+        let result = self.get(&url)?;
+
+        match regex_cap(&result, r#"window.code\s*=\s*(\d+)"#)? {
+            "200" => {
+                regex_cap(&result, r#"redirect_uri\s*=\s*"([^"]+)""#)
+                    .map(|uri| ScanStatus::Confirmed(uri.to_owned()))
+            }
+            "201" => {
+                // window.userAvatar = 'data:img/jpg;base64,...'
+                let avatar = regex_cap(&result, r#"userAvatar\s*=\s*'data:[^,]*;base64,([^']+)'"#)
+                    .ok()
+                    .and_then(base64_decode);
+
+                Ok(ScanStatus::Scanned(avatar))
+            }
+            "408" => Ok(ScanStatus::Waiting),
+            "400" => Ok(ScanStatus::Expired),
+            code => Err(Error::Protocol(format!("unexpected login code {}", code))),
+        }
+    }
+
+    /// finish the login from the scan redirect uri and initialize the session.
+    pub fn login(&self, redirect_uri: &str) -> Result<()> {
+
+        // webwxnewloginpage
+        let url = format!("{}&fun=new&version=v2", redirect_uri);
+        println!("login with: {}", url);
+        let response = self.transport.get(&url, &[])?;
+        let result = response.text();
+        println!("login result: {}", result);
+
+        let skey = regex_cap(&result, r#"<skey>(.*)</skey>"#)?;
+        let sid = regex_cap(&result, r#"<wxsid>(.*)</wxsid>"#)?;
+        let uin = regex_cap(&result, r#"<wxuin>(.*)</wxuin>"#)?;
+        let pass_ticket = regex_cap(&result, r#"<pass_ticket>(.*)</pass_ticket>"#)?;
+
+        {
+            let mut session = self.session.write().unwrap();
+            // sync keys and seen messages belong to the previous account
+            if session.uin != uin {
+                session.sync_keys = Value::Null;
+                session.sync_check_keys = Value::Null;
+                session.seen_msgs.clear();
+            }
+            session.uin = uin.to_owned();
+            session.skey = skey.to_owned();
+            session.sid = sid.to_owned();
+            session.pass_ticket = pass_ticket.to_owned();
+            session.set_cookies(&response.cookies);
+        }
+
+        self.init()
+    }
+
+    fn status_notify(&self) -> Result<()> {
+        let (url, data) = {
+            let session = self.session.read().unwrap();
+            let url = self.endpoints
+                .web_url(&format!("/cgi-bin/mmwebwx-bin/\
+                                   webwxstatusnotify?lang=zh_CN&pass_ticket={}",
+                                  session.pass_ticket));
+
+            (url, session.status_notify_data())
+        };
+
+        // TODO: check result
+        let _ = self.post(&url, &data)?;
+
+        Ok(())
+    }
+
+    fn init(&self) -> Result<()> {
+        let (url, data) = {
+            let session = self.session.read().unwrap();
+            let url = self.endpoints
+                .web_url(&format!("/cgi-bin/mmwebwx-bin/\
+                                   webwxinit?lang=zh_CN&pass_ticket={}&skey={}&r={}",
+                                  session.pass_ticket,
+                                  session.skey,
+                                  time_stamp()));
+
+            (url, session.base_data())
+        };
+        let json = self.post(&url, &data)?.parse::<Value>()?;
+        println!("{}", json["BaseResponse"]);
+        match json["BaseResponse"]["Ret"].as_i64() {
+            Some(0) => {}
+            ret => return Err(Error::Protocol(format!("webwxinit failed: {:?}", ret))),
+        }
+        {
+            let mut session = self.session.write().unwrap();
+            // a restored sync key makes `webwxsync` return what was missed
+            // while offline
+            if !session.sync_keys.is_array() {
+                session.set_sync_key(&json["SyncKey"]);
+                session.set_sync_check_key(&json["SyncKey"]);
+            }
+            session.set_user_info(&json);
+        }
+
+        let mut groups = vec![];
+        if let Value::Array(ref contact_list) = json["ContactList"] {
+            for contact in contact_list {
+                if let Some(name) = contact["UserName"].as_str() {
+                    if name.starts_with("@@") {
+                        groups.push(name.to_owned());
+                    }
+                }
+            }
+        }
+
+        self.fetch_groups(&groups)
+    }
+
+    /// fetch detail info of groups with `webwxbatchgetcontact`.
+    pub fn fetch_groups(&self, groups: &[String]) -> Result<()> {
+        let json = self.batch_get_contact(groups)?;
+
+        if let Value::Array(ref groups) = json["ContactList"] {
+            for group in groups {
+                let token = self.chat_token.fetch_add(1, Ordering::SeqCst);
+                self.append_chat(ChatRoom::from_json(group, token));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// fetch a known group again, e.g. after a membership notice, the
+    /// members who joined or left are reported with `GroupUpdated`.
+    pub fn refresh_group(&self, id: &str) -> Result<()> {
+        let json = self.batch_get_contact(&[id.to_owned()])?;
+
+        if let Value::Array(ref groups) = json["ContactList"] {
+            for group in groups {
+                self.update_contact(group);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn batch_get_contact(&self, names: &[String]) -> Result<Value> {
+        let (url, data) = {
+            let session = self.session.read().unwrap();
+            let url = self.endpoints
+                .web_url(&format!("/cgi-bin/mmwebwx-bin/\
+                                   webwxbatchgetcontact?type=ex&r={}&pass_ticket={}",
+                                  time_stamp(),
+                                  session.pass_ticket));
+
+            (url, session.group_info_data(names))
+        };
+
+        Ok(self.post(&url, &data)?.parse::<Value>()?)
+    }
+
+    pub fn fetch_contact(&self) -> Result<()> {
+        let url = {
+            let session = self.session.read().unwrap();
+            self.endpoints
+                .web_url(&format!("/cgi-bin/mmwebwx-bin/\
+                                   webwxgetcontact?pass_ticket={}&skey={}&r={}&seq=0",
+                                  session.pass_ticket,
+                                  session.skey,
+                                  time_stamp()))
+        };
+
+        let result = self.get(&url)?.parse::<Value>()?;
+        if let Value::Array(ref member_list) = result["MemberList"] {
+            for member in member_list {
+                self.append_user(User::from_json(member));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// poll `synccheck` and fetch new messages until the session ends for
+    /// good. transient failures are retried with an exponential backoff, a
+    /// session ended by the server is resumed if possible.
+    pub fn sync_loop(&self) -> Disconnect {
+        let mut failures = 0;
+
+        loop {
+            if self.is_cancelled() {
+                return Disconnect::Cancelled;
+            }
+
+            let result = self.sync_round();
+            // `close` may have been called while a request was in flight
+            if self.is_cancelled() {
+                return Disconnect::Cancelled;
+            }
+
+            match result {
+                Ok(None) => failures = 0,
+                Ok(Some(reason)) => {
+                    println!("session ended by server: {}", reason);
+                    if let Err(e) = self.resume_session() {
+                        println!("could not resume session: {}", e);
+                        return Disconnect::SessionExpired(reason.to_owned());
+                    }
+
+                    failures = 0;
+                }
+                Err(e) => {
+                    failures += 1;
+                    if failures > MAX_SYNC_RETRY {
+                        return Disconnect::Network(format!("{}", e));
+                    }
+
+                    let delay = backoff_delay(failures);
+                    println!("sync failed ({}/{}), retry in {:?}: {}",
+                             failures,
+                             MAX_SYNC_RETRY,
+                             delay,
+                             e);
+                    thread::sleep(delay);
+                }
+            }
+        }
+    }
+
+    /// one `synccheck` and whatever it asks for. returns the reason if the
+    /// server ended the session.
+    fn sync_round(&self) -> Result<Option<&'static str>> {
+        let result = self.sync_check()?;
+        self.check_cancelled()?;
+
+        match result {
+            SyncCheckResult::Idle => {}
+            // contact changes are delivered as delta lists of `webwxsync`
+            SyncCheckResult::NewMessage |
+            SyncCheckResult::ContactChanged |
+            SyncCheckResult::ContactListChanged |
+            SyncCheckResult::PhoneActivity |
+            SyncCheckResult::Unknown(_) => self.sync()?,
+            SyncCheckResult::Failed(retcode) => {
+                return Err(Error::Protocol(format!("synccheck failed, retcode {}", retcode)));
+            }
+            SyncCheckResult::LoggedOut |
+            SyncCheckResult::LoggedInElsewhere |
+            SyncCheckResult::SessionInvalid => return Ok(result.end_reason()),
+        }
+
+        Ok(None)
+    }
+
+    /// one `synccheck` round.
+    pub fn sync_check(&self) -> Result<SyncCheckResult> {
+        let (url, headers) = {
+            let session = self.session.read().unwrap();
+            let ts = time_stamp();
+            let url = self.endpoints
+                .push_url(&format!("/cgi-bin/mmwebwx-bin/synccheck\
+                                    ?sid={}&uin={}&skey={}&deviceid={}&synckey={}&r={}&_={}",
+                                   session.sid,
+                                   session.uin,
+                                   session.skey,
+                                   session.device_id,
+                                   session.sync_check_key_str(),
+                                   ts,
+                                   ts));
+
+            let mut headers = Headers::new();
+            if let Some(cookie) = session.headers.get::<Cookie>() {
+                headers.set(cookie.clone());
+            }
+            headers.set_raw("Accept", vec![b"*/*".to_vec()]);
+            headers.set_raw("Referer",
+                            vec![self.endpoints.push_referer().into_bytes()]);
+
+            (url, headers)
+        };
+
+        println!("sync check url: {}", url);
+
+        let result = self.transport.get(&url, &raw_headers(&headers))?.text();
+
+        let retcode = regex_cap(&result, r#"retcode:"(\d+)""#)?;
+        let selector = regex_cap(&result, r#"selector:"(\d+)""#)?;
+        let retcode: isize = retcode.parse().unwrap();
+        let selector: isize = selector.parse().unwrap();
+        println!("{} = {} - {}", result, retcode, selector);
+
+        Ok(SyncCheckResult::from_codes(retcode, selector))
+    }
+
+    /// `webwxsync`, applies contact changes and emits every new message.
+    pub fn sync(&self) -> Result<()> {
+        let (url, data) = {
+            let session = self.session.read().unwrap();
+            let url = self.endpoints
+                .web_url(&format!("/cgi-bin/mmwebwx-bin/webwxsync?sid={}&skey={}&pass_ticket={}",
+                                  session.sid,
+                                  session.skey,
+                                  session.pass_ticket));
+
+            (url, session.message_check_data())
+        };
+
+        let json: Value = self.post(&url, &data)?.parse()?;
+        match json["BaseResponse"]["Ret"].as_i64() {
+            Some(0) => {}
+            ret => return Err(Error::Protocol(format!("webwxsync failed: {:?}", ret))),
+        }
+
+        // `SyncKey` continues `webwxsync`, `SyncCheckKey` continues `synccheck`
+        let key_changed = {
+            let mut session = self.session.write().unwrap();
+            let old_key = session.sync_keys.clone();
+            session.set_sync_key(&json["SyncKey"]);
+            if json["SyncCheckKey"]["List"].is_array() {
+                session.set_sync_check_key(&json["SyncCheckKey"]);
+            } else {
+                session.set_sync_check_key(&json["SyncKey"]);
+            }
+
+            old_key != session.sync_keys
+        };
+
+        if let Value::Array(ref list) = json["ModContactList"] {
+            for contact in list {
+                self.update_contact(contact);
+            }
+        }
+
+        if let Value::Array(ref list) = json["DelContactList"] {
+            for contact in list {
+                if let Some(name) = contact["UserName"].as_str() {
+                    self.remove_contact(name);
+                }
+            }
+        }
+
+        if let Value::Array(ref list) = json["ModChatRoomMemberList"] {
+            for chat in list {
+                if chat["UserName"].as_str().map_or(false, |n| n.starts_with("@@")) {
+                    self.update_contact(chat);
+                }
+            }
+        }
+
+        let mut delivered = false;
+        if let Value::Array(ref list) = json["AddMsgList"] {
+            for msg in list {
+                println!("got message =========================\n {}", msg);
+
+                // a message may be delivered again, e.g. after a reconnect
+                let msg_id = msg["MsgId"].as_str().unwrap_or("");
+                if !msg_id.is_empty() && !self.session.write().unwrap().mark_seen(msg_id) {
+                    println!("drop duplicate message {}", msg_id);
+                    continue;
+                }
+                delivered = true;
+
+                // 51 is wechat init message, also sent when a chat is
+                // opened on the phone
+                if msg["MsgType"].as_i64() == Some(51) {
+                    if let Err(e) = self.status_notified(msg) {
+                        println!("status notify failed: {}", e);
+                    }
+                    continue;
+                }
+
+                self.emit(Event::Message(Message::from_json(msg)));
+            }
+        }
+
+        // keep the acknowledged sync key and seen messages across restarts
+        if key_changed || delivered {
+            let session = self.session.read().unwrap().to_json();
+            self.emit(Event::SessionUpdated(session));
+        }
+
+        Ok(())
+    }
+
+    /// pick up groups listed in a status notify message that are not known
+    /// yet, e.g. a chat entered on the phone.
+    fn status_notified(&self, msg: &Value) -> Result<()> {
+        let names = msg["StatusNotifyUserName"].as_str().unwrap_or("");
+        let groups: Vec<String> = {
+            let chat_list = self.chat_list.read().unwrap();
+            names.split(',')
+                .filter(|n| n.starts_with("@@"))
+                .filter(|n| !chat_list.iter().any(|c| c.id() == *n))
+                .map(|n| n.to_owned())
+                .collect()
+        };
+
+        if groups.is_empty() {
+            return Ok(());
+        }
+
+        self.fetch_groups(&groups)
+    }
+
+    /// accept a friend request, the new contact is reported with
+    /// `ContactAdded`.
+    pub fn accept_friend(&self, request: &FriendRequest) -> Result<()> {
+
+        println!("accept friend: {} ({})", request.nick_name, request.user_name);
+
+        self.verify_user(3, &request.user_name, &request.ticket, "")?;
+
+        let json = self.batch_get_contact(&[request.user_name.clone()])?;
+        if let Value::Array(ref contacts) = json["ContactList"] {
+            for contact in contacts {
+                self.update_contact(contact);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// send a friend request, the contact is reported with `ContactAdded`
+    /// once it is accepted.
+    pub fn add_friend(&self, user_name: &str, message: &str) -> Result<()> {
+
+        println!("add friend: {}: {}", user_name, message);
+
+        self.verify_user(2, user_name, "", message)
+    }
+
+    /// `webwxverifyuser`, `opcode` is 2 to send a friend request and 3 to
+    /// accept one.
+    fn verify_user(&self,
+                   opcode: i64,
+                   user_name: &str,
+                   ticket: &str,
+                   content: &str)
+                   -> Result<()> {
+        let (url, data) = {
+            let session = self.session.read().unwrap();
+            let url = self.endpoints
+                .web_url(&format!("/cgi-bin/mmwebwx-bin/webwxverifyuser?r={}&pass_ticket={}",
+                                  time_stamp(),
+                                  session.pass_ticket));
+
+            (url, session.verify_user_data(opcode, user_name, ticket, content))
+        };
+
+        let json: Value = self.post(&url, &data)?.parse()?;
+        match json["BaseResponse"]["Ret"].as_i64() {
+            Some(0) => Ok(()),
+            ret => Err(Error::Protocol(format!("webwxverifyuser failed: {:?}", ret))),
+        }
+    }
+
+    fn remember_sent(&self, json: &Value, who: &str) {
+        if let Some(sent) = SentMessage::from_json(json, who) {
+            self.last_sent.lock().unwrap().insert(who.to_owned(), sent);
+        }
+    }
+
+    /// the last message sent to `who`, if it is still young enough to revoke.
+    pub fn revocable(&self, who: &str) -> Result<SentMessage> {
+        let sent = match self.last_sent.lock().unwrap().get(who) {
+            Some(sent) => sent.clone(),
+            None => return Err(Error::NotRevocable("No message to recall.")),
+        };
+
+        if time::get_time().sec - sent.time > REVOKE_WINDOW_SECS {
+            return Err(Error::NotRevocable("Messages can only be recalled within 2 minutes."));
+        }
+
+        Ok(sent)
+    }
+
+    /// `webwxrevokemsg`, withdraw a message sent by `send_message` and friends.
+    pub fn revoke(&self, sent: &SentMessage) -> Result<()> {
+
+        println!("revoke: {}: {}", sent.to, sent.msg_id);
+
+        let (url, data) = {
+            let session = self.session.read().unwrap();
+            let url = self.endpoints.web_url("/cgi-bin/mmwebwx-bin/webwxrevokemsg");
+
+            (url, session.revoke_data(sent))
+        };
+
+        let json: Value = self.post(&url, &data)?.parse()?;
+        match json["BaseResponse"]["Ret"].as_i64() {
+            Some(0) => {
+                let mut last_sent = self.last_sent.lock().unwrap();
+                if last_sent.get(&sent.to).map_or(false, |s| s.msg_id == sent.msg_id) {
+                    last_sent.remove(&sent.to);
+                }
+                Ok(())
+            }
+            ret => Err(Error::Protocol(format!("webwxrevokemsg failed: {:?}", ret))),
+        }
+    }
+
+    pub fn send_message(&self, who: &str, msg: &str) -> Result<()> {
+
+        println!("send_message: {}: {}", who, msg);
+
+        let (url, data) = {
+            let session = self.session.read().unwrap();
+            let url = self.endpoints
+                .web_url(&format!("/cgi-bin/mmwebwx-bin/webwxsendmsg?pass_ticket={}",
+                                  session.pass_ticket));
+
+            (url, session.message_send_data(who, msg))
+        };
+
+        let json: Value = self.post(&url, &data)?.parse()?;
+        match json["BaseResponse"]["Ret"].as_i64() {
+            Some(0) => Ok(self.remember_sent(&json, who)),
+            ret => Err(Error::Protocol(format!("webwxsendmsg failed: {:?}", ret))),
+        }
+    }
+
+    /// upload an image to `who` and send it.
+    pub fn send_image(&self, who: &str, file_name: &str, data: &[u8]) -> Result<()> {
+
+        println!("send_image: {}: {} ({} bytes)", who, file_name, data.len());
+
+        let media_id = self.upload_media(who, file_name, "pic", data, |_| true)?;
+
+        let (url, data) = {
+            let session = self.session.read().unwrap();
+            let url = self.endpoints
+                .web_url(&format!("/cgi-bin/mmwebwx-bin/webwxsendmsgimg?fun=async&f=json&\
+                                   pass_ticket={}",
+                                  session.pass_ticket));
+
+            (url, session.image_send_data(who, &media_id))
+        };
+
+        let json: Value = self.post(&url, &data)?.parse()?;
+        match json["BaseResponse"]["Ret"].as_i64() {
+            Some(0) => Ok(self.remember_sent(&json, who)),
+            ret => Err(Error::Protocol(format!("webwxsendmsgimg failed: {:?}", ret))),
+        }
+    }
+
+    /// `webwxuploadmedia`, returns the `MediaId` to send. `media_type` is
+    /// `pic` for images and `doc` for anything else. large files are sent in
+    /// chunks, `progress` gets the bytes uploaded so far after each one and
+    /// stops the upload by returning false.
+    pub fn upload_media<F>(&self,
+                           who: &str,
+                           file_name: &str,
+                           media_type: &str,
+                           data: &[u8],
+                           mut progress: F)
+                           -> Result<String>
+        where F: FnMut(usize) -> bool
+    {
+        let (url, mut headers, request, data_ticket, pass_ticket) = {
+            let session = self.session.read().unwrap();
+            let url = self.endpoints.file_url("/cgi-bin/mmwebwx-bin/webwxuploadmedia?f=json");
+
+            (url,
+             session.headers.clone(),
+             session.upload_media_data(who, data.len()),
+             session.data_ticket(),
+             session.pass_ticket.clone())
+        };
+
+        let boundary = format!("----WebKitFormBoundary{}", time_stamp());
+        headers.set_raw("Content-Type",
+                        vec![format!("multipart/form-data; boundary={}", boundary).into_bytes()]);
+
+        let parts: Vec<&[u8]> = if data.is_empty() {
+            vec![data]
+        } else {
+            data.chunks(UPLOAD_CHUNK_SIZE).collect()
+        };
+        let chunks = parts.len();
+
+        let mut json = Value::Null;
+        for (chunk, part) in parts.into_iter().enumerate() {
+            let mut body = Vec::new();
+            {
+                let mut field = |name: &str, value: &str| {
+                    body.extend(format!("--{}\r\nContent-Disposition: form-data; \
+                                         name=\"{}\"\r\n\r\n{}\r\n",
+                                        boundary,
+                                        name,
+                                        value)
+                                        .into_bytes());
+                };
+                field("id", "WU_FILE_0");
+                field("name", file_name);
+                field("type", mime_type(file_name));
+                field("lastModifiedDate", &time::now().rfc822().to_string());
+                field("size", &data.len().to_string());
+                if chunks > 1 {
+                    field("chunks", &chunks.to_string());
+                    field("chunk", &chunk.to_string());
+                }
+                field("mediatype", media_type);
+                field("uploadmediarequest", &request.to_string());
+                field("webwx_data_ticket", &data_ticket);
+                field("pass_ticket", &pass_ticket);
+            }
+            body.extend(format!("--{}\r\nContent-Disposition: form-data; name=\"filename\"; \
+                                 filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+                                boundary,
+                                file_name,
+                                mime_type(file_name))
+                                .into_bytes());
+            body.extend_from_slice(part);
+            body.extend(format!("\r\n--{}--\r\n", boundary).into_bytes());
+
+            println!("upload media: {} chunk {}/{}", file_name, chunk + 1, chunks);
+            let result = self.transport.post(&url, &raw_headers(&headers), &body)?.text();
+            print_result(&result);
+
+            json = result.parse()?;
+            match json["BaseResponse"]["Ret"].as_i64() {
+                Some(0) | None => {}
+                ret => return Err(Error::Protocol(format!("upload failed: {:?}", ret))),
+            }
+
+            if !progress(chunk * UPLOAD_CHUNK_SIZE + part.len()) {
+                return Err(Error::Cancelled);
+            }
+        }
+
+        match json["MediaId"].as_str() {
+            Some(media_id) if !media_id.is_empty() => Ok(media_id.to_owned()),
+            _ => Err(Error::Protocol(format!("upload failed: {}", json["BaseResponse"]))),
+        }
+    }
+
+    /// upload a file to `who` and send it as an attachment.
+    pub fn send_file<F>(&self, who: &str, file_name: &str, data: &[u8], progress: F) -> Result<()>
+        where F: FnMut(usize) -> bool
+    {
+        println!("send_file: {}: {} ({} bytes)", who, file_name, data.len());
+
+        let media_id = self.upload_media(who, file_name, "doc", data, progress)?;
+
+        let (url, data) = {
+            let session = self.session.read().unwrap();
+            let url = self.endpoints
+                .web_url(&format!("/cgi-bin/mmwebwx-bin/webwxsendappmsg?fun=async&f=json&\
+                                   pass_ticket={}",
+                                  session.pass_ticket));
+
+            (url, session.file_send_data(who, file_name, data.len(), &media_id))
+        };
+
+        let json: Value = self.post(&url, &data)?.parse()?;
+        match json["BaseResponse"]["Ret"].as_i64() {
+            Some(0) => Ok(self.remember_sent(&json, who)),
+            ret => Err(Error::Protocol(format!("webwxsendappmsg failed: {:?}", ret))),
+        }
+    }
+
+    /// download the attachment of a file message (MsgType 49, AppMsgType 6).
+    pub fn get_media<W, F>(&self, msg: &Value, out: &mut W, progress: F) -> Result<usize>
+        where W: Write,
+              F: FnMut(usize) -> bool
+    {
+        let url = {
+            let session = self.session.read().unwrap();
+            self.endpoints
+                .file_url(&format!("/cgi-bin/mmwebwx-bin/webwxgetmedia?sender={}&mediaid={}&\
+                                    encryfilename={}&fromuser={}&pass_ticket={}&\
+                                    webwx_data_ticket={}",
+                                   msg["FromUserName"].as_str().unwrap_or(""),
+                                   msg["MediaId"].as_str().unwrap_or(""),
+                                   msg["EncryFileName"].as_str().unwrap_or(""),
+                                   session.uin,
+                                   session.pass_ticket,
+                                   session.data_ticket()))
+        };
+
+        self.get_ranges(&url, out, progress)
+    }
+
+    /// end the session on the server side.
+    pub fn logout(&self) -> Result<()> {
+        let (url, headers, body) = {
+            let session = self.session.read().unwrap();
+            if !session.has_credentials() {
+                return Ok(());
+            }
+
+            let url = self.endpoints
+                .web_url(&format!("/cgi-bin/mmwebwx-bin/webwxlogout?redirect=1&type=1&skey={}",
+                                  session.skey));
+            let mut headers = session.headers.clone();
+            headers.set_raw("Content-Type",
+                            vec![b"application/x-www-form-urlencoded".to_vec()]);
+            let body = format!("sid={}&uin={}", session.sid, session.uin);
+
+            (url, headers, body)
+        };
+
+        println!("logout: {}", url);
+        self.transport.post(&url, &raw_headers(&headers), body.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// download the image of message `msg_id`.
+    pub fn get_msg_image(&self, msg_id: &str) -> Result<Vec<u8>> {
+        let url = {
+            let session = self.session.read().unwrap();
+            self.endpoints
+                .web_url(&format!("/cgi-bin/mmwebwx-bin/webwxgetmsgimg?&MsgID={}&skey={}",
+                                  msg_id,
+                                  session.skey))
+        };
+
+        self.get_bytes(&url)
+    }
+
+    /// download the thumbnail of a video message (MsgType 43 and 62).
+    pub fn get_video_thumbnail(&self, msg_id: &str) -> Result<Vec<u8>> {
+        let url = {
+            let session = self.session.read().unwrap();
+            self.endpoints
+                .web_url(&format!("/cgi-bin/mmwebwx-bin/webwxgetmsgimg?&MsgID={}&skey={}&\
+                                   type=slave",
+                                  msg_id,
+                                  session.skey))
+        };
+
+        self.get_bytes(&url)
+    }
+
+    /// download the video of a video message into `out`, the server only
+    /// answers range requests. stops when the client is cancelled.
+    pub fn get_video<W: Write>(&self, msg_id: &str, out: &mut W) -> Result<usize> {
+        let url = {
+            let session = self.session.read().unwrap();
+            self.endpoints
+                .web_url(&format!("/cgi-bin/mmwebwx-bin/webwxgetvideo?msgid={}&skey={}",
+                                  msg_id,
+                                  session.skey))
+        };
+
+        self.get_ranges(&url, out, |_| !self.is_cancelled())
+    }
+
+    /// download the audio of a voice message (MsgType 34), mp3 encoded.
+    pub fn get_voice(&self, msg_id: &str) -> Result<Vec<u8>> {
+        let url = {
+            let session = self.session.read().unwrap();
+            self.endpoints
+                .web_url(&format!("/cgi-bin/mmwebwx-bin/webwxgetvoice?msgid={}&skey={}",
+                                  msg_id,
+                                  session.skey))
+        };
+
+        self.get_bytes(&url)
+    }
+
+    /// download an avatar, `url` is the `HeadImgUrl` of a contact or group,
+    /// which points to `webwxgeticon` or `webwxgetheadimg`.
+    pub fn get_head_image(&self, url: &str) -> Result<Vec<u8>> {
+        if url.starts_with("http") {
+            self.get_bytes(url)
+        } else {
+            self.get_bytes(&self.endpoints.web_url(url))
+        }
+    }
+
+    /// download a resource outside of the wechat session, e.g. from a cdn.
+    pub fn download(&self, url: &str) -> Result<Vec<u8>> {
+        let response = self.transport.get(url, &[])?;
+        println!("fetch: {} {} {}", url, response.status, response.body.len());
+
+        Ok(response.body)
+    }
+
+    pub fn user_name(&self) -> String {
+        self.session.read().unwrap().user_name().to_owned()
+    }
+
+    pub fn nick_name(&self) -> String {
+        self.session.read().unwrap().nick_name().to_owned()
+    }
+
+    /// `HeadImgUrl` of the logged in user.
+    pub fn head_img_url(&self) -> String {
+        self.session.read().unwrap().head_img_url().to_owned()
+    }
+
+    pub fn is_contact(&self, user_name: &str) -> bool {
+        self.user_list.read().unwrap().iter().any(|u| u.user_name() == user_name)
+    }
+
+    fn append_user(&self, user: User) {
+        if self.user_list.write().unwrap().insert(user.clone()) {
+            self.emit(Event::ContactAdded(user));
+        }
+    }
+
+    /// apply one entry of `ModContactList`.
+    fn update_contact(&self, json: &Value) {
+        let name = match json["UserName"].as_str() {
+            Some(name) => name,
+            None => return,
+        };
+
+        if !name.starts_with("@@") {
+            let user = User::from_json(json);
+            let old = self.user_list.write().unwrap().replace(user.clone());
+            match old {
+                None => self.emit(Event::ContactAdded(user)),
+                Some(ref old) if old.nick_name() != user.nick_name() ||
+                                 old.alias() != user.alias() ||
+                                 old.head_img_url() != user.head_img_url() => {
+                    self.emit(Event::ContactUpdated(user))
+                }
+                Some(_) => {}
+            }
+            return;
+        }
+
+        // update in place, the chat keeps its token
+        let updated = {
+            let mut chat_list = self.chat_list.write().unwrap();
+            let old = chat_list.iter().find(|c| c.id() == name).cloned();
+            old.map(|mut chat| {
+                let changed = (chat.alias(), chat.head_img_url());
+                let (joined, left) = chat.update(json);
+                chat_list.replace(chat.clone());
+                (changed, chat, joined, left)
+            })
+        };
+
+        let ((alias, head_img_url), chat, joined, left) = match updated {
+            Some(updated) => updated,
+            None => {
+                let token = self.chat_token.fetch_add(1, Ordering::SeqCst);
+                return self.append_chat(ChatRoom::from_json(json, token));
+            }
+        };
+
+        if alias != chat.alias() || head_img_url != chat.head_img_url() || !joined.is_empty() ||
+           !left.is_empty() {
+            self.emit(Event::GroupUpdated(chat, joined, left));
+        }
+    }
+
+    /// apply one entry of `DelContactList`.
+    fn remove_contact(&self, name: &str) {
+        if name.starts_with("@@") {
+            let chat = self.find_chat_by_id(name);
+            if let Some(chat) = chat {
+                self.chat_list.write().unwrap().remove(&chat);
+                self.emit(Event::GroupRemoved(chat));
+            }
+            return;
+        }
+
+        let removed = {
+            let mut user_list = self.user_list.write().unwrap();
+            let user = user_list.iter().find(|u| u.user_name() == name).cloned();
+            user.map_or(false, |u| user_list.remove(&u))
+        };
+
+        if removed {
+            self.emit(Event::ContactRemoved(name.to_owned()));
+        }
+    }
+
+    fn append_chat(&self, chat: ChatRoom) {
+        let mut chat_list = self.chat_list.write().unwrap();
+
+        // chats are ordered by token, a re-fetched chat gets a new one
+        if chat_list.iter().any(|c| c.id() == chat.id()) {
+            return;
+        }
+
+        if chat_list.insert(chat.clone()) {
+            self.emit(Event::GroupAdded(chat));
+        }
+    }
+
+    pub fn chat_list(&self) -> Vec<ChatRoom> {
+        self.chat_list.read().unwrap().iter().cloned().collect()
+    }
+
+    /// forget all contacts and chats.
+    pub fn clear(&self) {
+        self.user_list.write().unwrap().clear();
+        self.chat_list.write().unwrap().clear();
+    }
+
+    pub fn find_chat_by_token(&self, token: usize) -> Option<ChatRoom> {
+        self.chat_list
+            .read()
+            .unwrap()
+            .iter()
+            .find(|c| c.token() == token)
+            .cloned()
+    }
+
+    pub fn find_chat_by_id(&self, id: &str) -> Option<ChatRoom> {
+        self.chat_list
+            .read()
+            .unwrap()
+            .iter()
+            .find(|c| c.id() == id)
+            .cloned()
+    }
+
+    /// fetch `url` in ranged requests, writing each part to `out` as it
+    /// arrives. `progress` gets the bytes received so far, returning false
+    /// cancels the download.
+    fn get_ranges<W, F>(&self, url: &str, out: &mut W, mut progress: F) -> Result<usize>
+        where W: Write,
+              F: FnMut(usize) -> bool
+    {
+        let headers = {
+            self.session.read().unwrap().headers.clone()
+        };
+
+        let mut received = 0;
+        loop {
+            let mut headers = headers.clone();
+            let range = format!("bytes={}-{}", received, received + DOWNLOAD_CHUNK_SIZE - 1);
+            headers.set_raw("Range", vec![range.into_bytes()]);
+
+            let response = self.transport.get(url, &raw_headers(&headers))?;
+            println!("fetched: {} {} {}", url, response.status, response.body.len());
+
+            let done = match response.status {
+                // the whole content, the server ignored the range
+                200 if received == 0 => true,
+                206 => response.body.len() < DOWNLOAD_CHUNK_SIZE,
+                // the last part ended exactly at the end
+                416 if received > 0 => return Ok(received),
+                status => return Err(Error::Protocol(format!("download failed: {}", status))),
+            };
+
+            out.write_all(&response.body).map_err(transport::Error::from)?;
+            received += response.body.len();
+
+            if !progress(received) {
+                return Err(Error::Cancelled);
+            }
+            if done {
+                return Ok(received);
+            }
+        }
+    }
+
+    fn get_bytes(&self, url: &str) -> Result<Vec<u8>> {
+        let headers = {
+            self.session.read().unwrap().headers.clone()
+        };
+
+        let response = self.transport.get(url, &raw_headers(&headers))?;
+        println!("fetched: {} {} {}", url, response.status, response.body.len());
+
+        Ok(response.body)
+    }
+
+    fn get(&self, url: &str) -> Result<String> {
+
+        let headers = {
+            self.session.read().unwrap().headers.clone()
+        };
+
+        println!("get: {:?}", url);
+        let result = self.transport.get(url, &raw_headers(&headers))?.text();
+        print_result(&result);
+
+        Ok(result)
+    }
+
+    fn post(&self, url: &str, data: &Value) -> Result<String> {
+
+        let headers = {
+            self.session.read().unwrap().headers.clone()
+        };
+        println!("post: {:?}\nheaders:{:?}\npost_data: {:?}",
+                 url,
+                 headers,
+                 data);
+        let result = self.transport
+            .post(url, &raw_headers(&headers), data.to_string().as_bytes())?
+            .text();
+        print_result(&result);
+
+        Ok(result)
+    }
+}
+
+/// content type of an upload, guessed from its file name.
+fn mime_type(file_name: &str) -> &'static str {
+    let ext = file_name.rsplit('.').next().unwrap_or("").to_lowercase();
+    match ext.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "bmp" => "image/bmp",
+        _ => "application/octet-stream",
+    }
+}
+
+fn print_result(result: &str) {
+    if result.len() > 500 {
+        // cut on a char boundary, nick names are often not ascii
+        let end = (0..301).rev().find(|&i| result.is_char_boundary(i)).unwrap_or(0);
+        println!("result: {}", &result[0..end]);
+    } else {
+        println!("result: {}", result);
+    }
+}
+
+pub fn time_stamp() -> i64 {
+    time::get_time().sec * 1000
+}
+
+fn backoff_delay(failures: u32) -> Duration {
+    let secs = 1u64 << cmp::min(failures.saturating_sub(1), 16);
+
+    Duration::from_secs(cmp::min(secs, MAX_BACKOFF_SECS))
+}
+
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let mut buf = Vec::with_capacity(input.len() * 3 / 4);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+
+    for c in input.bytes() {
+        let v = match c {
+            b'A'...b'Z' => c - b'A',
+            b'a'...b'z' => c - b'a' + 26,
+            b'0'...b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            b'\r' | b'\n' | b' ' => continue,
+            _ => return None,
+        };
+
+        acc = (acc << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            buf.push((acc >> bits) as u8);
+        }
+    }
+
+    Some(buf)
+}
+
+fn regex_cap<'a>(c: &'a str, r: &str) -> Result<&'a str> {
+    let reg = Regex::new(r).unwrap();
+
+    match reg.captures(c).and_then(|caps| caps.get(1)) {
+        Some(m) => Ok(m.as_str()),
+        None => Err(Error::Protocol(format!("unexpected response: {}", c))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Session {
+        Session::new(&Endpoints::with_base("http://stand-in"))
+    }
+
+    #[test]
+    fn sync_check_codes() {
+        assert_eq!(SyncCheckResult::from_codes(0, 0), SyncCheckResult::Idle);
+        assert_eq!(SyncCheckResult::from_codes(0, 2), SyncCheckResult::NewMessage);
+        assert_eq!(SyncCheckResult::from_codes(0, 4), SyncCheckResult::ContactChanged);
+        assert_eq!(SyncCheckResult::from_codes(0, 6), SyncCheckResult::ContactListChanged);
+        assert_eq!(SyncCheckResult::from_codes(0, 7), SyncCheckResult::PhoneActivity);
+        assert_eq!(SyncCheckResult::from_codes(0, 3), SyncCheckResult::Unknown(3));
+        assert_eq!(SyncCheckResult::from_codes(1100, 0), SyncCheckResult::LoggedOut);
+        assert_eq!(SyncCheckResult::from_codes(1101, 2), SyncCheckResult::LoggedInElsewhere);
+        assert_eq!(SyncCheckResult::from_codes(1102, 0), SyncCheckResult::SessionInvalid);
+        assert_eq!(SyncCheckResult::from_codes(1205, 0), SyncCheckResult::Failed(1205));
+
+        assert!(SyncCheckResult::from_codes(0, 2).end_reason().is_none());
+        assert!(SyncCheckResult::from_codes(1101, 0).end_reason().is_some());
+    }
+
+    #[test]
+    fn set_cookies_replaces_same_name() {
+        let mut session = session();
+        session.set_cookies(&["a=1; Path=/".to_owned(), "b=2; Domain=x".to_owned()]);
+        session.set_cookies(&["a=3; Path=/".to_owned(), "; Path=/".to_owned()]);
+
+        assert_eq!(session.cookies(), vec!["b=2".to_owned(), "a=3".to_owned()]);
+    }
+
+    #[test]
+    fn session_round_trip() {
+        let mut session = session();
+        session.uin = "1001".to_owned();
+        session.sid = "sid".to_owned();
+        session.skey = "@crypt".to_owned();
+        session.pass_ticket = "pt".to_owned();
+        session.sync_keys = json!([{ "Key": 1, "Val": 2 }]);
+        session.sync_check_keys = json!([{ "Key": 1, "Val": 3 }]);
+        session.set_cookies(&["webwx_data_ticket=dt".to_owned()]);
+        session.mark_seen("m1");
+
+        let mut restored = self::session();
+        restored.restore(&session.to_json());
+
+        assert_eq!(restored.to_json(), session.to_json());
+        assert!(restored.has_credentials());
+        assert_eq!(restored.device_id, session.device_id);
+        assert_eq!(restored.sync_check_key_str(), "1_3");
+        assert!(!restored.mark_seen("m1"));
+    }
+
+    #[test]
+    fn only_recent_seen_ids_are_saved() {
+        let mut session = session();
+        for i in 0..MAX_SAVED_SEEN_MSGS + 10 {
+            session.mark_seen(&i.to_string());
+        }
+
+        let mut restored = self::session();
+        restored.restore(&session.to_json());
+
+        assert_eq!(restored.seen_msgs.len(), MAX_SAVED_SEEN_MSGS);
+        assert!(restored.mark_seen("9"));
+        assert!(!restored.mark_seen("10"));
+    }
+
+    #[test]
+    fn restore_old_session() {
+        // saved before `SyncCheckKey` and `SeenMsgIds` existed
+        let mut session = session();
+        session.restore(&json!({ "Uin": "1", "Sid": "s", "SyncKey": [{ "Key": 1, "Val": 2 }] }));
+
+        assert_eq!(session.sync_check_key_str(), "1_2");
+        assert!(session.seen_msgs.is_empty());
+    }
+
+    #[test]
+    fn decode_base64() {
+        assert_eq!(base64_decode("YXZhdGFy"), Some(b"avatar".to_vec()));
+        assert_eq!(base64_decode("YQ=="), Some(b"a".to_vec()));
+        assert_eq!(base64_decode("YW\nJj"), Some(b"abc".to_vec()));
+        assert_eq!(base64_decode(""), Some(vec![]));
+        assert_eq!(base64_decode("YQ*"), None);
+    }
+
+    #[test]
+    fn backoff_is_exponential_and_bounded() {
+        assert_eq!(backoff_delay(1), Duration::from_secs(1));
+        assert_eq!(backoff_delay(2), Duration::from_secs(2));
+        assert_eq!(backoff_delay(4), Duration::from_secs(8));
+        assert_eq!(backoff_delay(6), Duration::from_secs(MAX_BACKOFF_SECS));
+        assert_eq!(backoff_delay(100), Duration::from_secs(MAX_BACKOFF_SECS));
+    }
+
+    #[test]
+    fn long_results_are_cut_on_char_boundaries() {
+        print_result(&"你好".repeat(200));
+        print_result(&format!("a{}", "你好".repeat(200)));
+    }
+
+    #[test]
+    fn group_message_has_sender() {
+        let msg = Message::from_json(&json!({
+            "MsgId": "1", "MsgType": 1, "FromUserName": "@@group", "ToUserName": "@me",
+            "Content": "@member:<br/>hi<br/>there", "CreateTime": 1500000000,
+        }));
+
+        assert_eq!(msg.sender, "@member");
+        assert_eq!(msg.content, "hi<br/>there");
+        assert_eq!(msg.time, 1500000000);
+
+        let msg = Message::from_json(&json!({ "FromUserName": "@friend", "Content": "@a:b" }));
+        assert_eq!(msg.sender, "");
+        assert_eq!(msg.content, "@a:b");
+    }
+
+    #[test]
+    fn file_message_escapes_name() {
+        let data = session().file_send_data("@friend", "a<b>&c.t\"xt", 3, "@media");
+        let content = data["Msg"]["Content"].as_str().unwrap();
+        assert!(content.contains("<title>a&lt;b&gt;&amp;c.t&quot;xt</title>"));
+        assert!(content.contains("<fileext>t&quot;xt</fileext>"));
+    }
+}
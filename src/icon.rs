@@ -0,0 +1,166 @@
+
+extern crate std;
+extern crate regex;
+
+use self::regex::Regex;
+use purple_sys::*;
+use client::Client;
+use message::SrvMsg;
+use server::Connection;
+use std::os::raw::c_void;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::ptr::null_mut;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// cached avatars older than this are removed. they are named after the
+/// `UserName` of their owner, which only lasts as long as the session.
+const MAX_ICON_AGE_SECS: u64 = 7 * 24 * 3600;
+
+/// an avatar to fetch, for a contact, a group (`@@` names) or ourselves.
+pub struct IconRequest {
+    name: String,
+    /// the `HeadImgUrl` of the contact.
+    url: String,
+}
+
+impl IconRequest {
+    pub fn new(name: &str, url: &str) -> IconRequest {
+        IconRequest {
+            name: name.to_owned(),
+            url: url.to_owned(),
+        }
+    }
+}
+
+/// start the worker fetching avatars of one connection, one at a time. it
+/// stops once the returned sender is dropped.
+pub unsafe fn fetcher(client: Arc<Client>, sender: Sender<SrvMsg>) -> Sender<IconRequest> {
+
+    let dir = icon_cache_dir();
+    let (tx, rx) = channel::<IconRequest>();
+
+    thread::spawn(move || {
+        prune(&dir);
+
+        for request in rx {
+            if client.is_cancelled() {
+                break;
+            }
+
+            let checksum = checksum(&request.url);
+            let path = dir.join(format!("{}-{}", request.name, checksum));
+
+            if let Some(data) = fetch(&client, &request, &path) {
+                let _ = sender.send(SrvMsg::SetIcon(request.name, data, checksum));
+            }
+        }
+    });
+
+    tx
+}
+
+/// the avatar from the disk cache, or from the server and then cached.
+fn fetch(client: &Client, request: &IconRequest, path: &Path) -> Option<Vec<u8>> {
+
+    let mut data = Vec::new();
+    if let Ok(len) = File::open(path).and_then(|mut f| f.read_to_end(&mut data)) {
+        if len > 0 {
+            return Some(data);
+        }
+    }
+
+    let data = match client.get_head_image(&request.url) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("fetch icon of {} failed: {}", request.name, e);
+            return None;
+        }
+    };
+
+    if data.is_empty() {
+        return None;
+    }
+
+    if let Err(e) = File::create(path).and_then(|mut f| f.write_all(&data)) {
+        println!("save icon {:?} failed: {}", path, e);
+    }
+
+    Some(data)
+}
+
+/// directory avatars are kept in, named after their owner and checksum.
+unsafe fn icon_cache_dir() -> PathBuf {
+
+    let dir = Path::new(CStr::from_ptr(purple_user_dir()).to_string_lossy().as_ref())
+        .join("wechat")
+        .join("icons");
+    if let Err(e) = fs::create_dir_all(&dir) {
+        println!("create icon cache {:?} failed: {}", dir, e);
+    }
+
+    dir
+}
+
+/// remove avatars of sessions long gone.
+fn prune(dir: &Path) {
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    let max_age = Duration::from_secs(MAX_ICON_AGE_SECS);
+    for entry in entries.filter_map(|e| e.ok()) {
+        let modified = entry.metadata().and_then(|m| m.modified());
+        let age = modified.ok().and_then(|t| SystemTime::now().duration_since(t).ok());
+        if age.map_or(false, |age| age > max_age) {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// the `seq` of a head image url, it changes with the avatar.
+fn checksum(url: &str) -> String {
+    let regex = Regex::new(r#"[?&]seq=(\d+)"#).unwrap();
+
+    regex.captures(url)
+        .map(|caps| caps.get(1).unwrap().as_str().to_owned())
+        .unwrap_or_else(|| "0".to_owned())
+}
+
+/// hand a fetched avatar to libpurple, which takes ownership of a copy. our
+/// own avatar becomes the icon of the account.
+pub unsafe fn set_icon(conn: &Connection, name: &str, data: &[u8], checksum: &str) {
+
+    if name == conn.client().user_name() {
+        let icon = g_memdup(data.as_ptr() as *const c_void, data.len() as _);
+        purple_buddy_icons_set_account_icon(conn.account(), icon as *mut u8, data.len() as _);
+    } else if name.starts_with("@@") {
+        let chat = conn.blist_chat(name);
+        if chat == null_mut() {
+            return;
+        }
+
+        // a PurpleChat starts with its blist node
+        let icon = g_memdup(data.as_ptr() as *const c_void, data.len() as _);
+        purple_buddy_icons_node_set_custom_icon(chat as *mut PurpleBlistNode,
+                                                icon as *mut u8,
+                                                data.len() as _);
+    } else {
+        let name = CString::new(name).unwrap();
+        let checksum = CString::new(checksum).unwrap();
+        let icon = g_memdup(data.as_ptr() as *const c_void, data.len() as _);
+        purple_buddy_icons_set_for_user(conn.account(),
+                                        name.as_ptr(),
+                                        icon,
+                                        data.len() as _,
+                                        checksum.as_ptr());
+    }
+}
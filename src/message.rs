@@ -1,32 +1,30 @@
 
-extern crate std;
-
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::Mutex;
 use serde_json::Value;
-use chatroom::ChatRoom;
-use user::User;
-
-lazy_static!{
-    pub static ref SRV_MSG: (Mutex<Sender<SrvMsg>>, Mutex<Receiver<SrvMsg>>) = {
-        let (tx, rx) = channel();
-        (Mutex::new(tx), Mutex::new(rx))
-    };
-}
+use pointer::Pointer;
+use std::path::PathBuf;
 
+/// messages worker threads post back to the main loop of their connection.
 #[derive(Debug)]
 pub enum SrvMsg {
     ShowMessageBox(String),
-    ShowVerifyImage(String),
-    AddContact(User),
-    AddGroup(ChatRoom),
-    MessageReceived(Value),
-    AppendImageMessage(i32, Value),
+    /// an image fetched for a message, added to the imgstore on the main
+    /// thread.
+    AppendImageMessage(Vec<u8>, Value),
+    /// render the given html for a message whose media was fetched.
+    AppendMediaMessage(String, Value),
+    /// like `AppendMediaMessage`, preceded by the image at the given path.
+    AppendThumbnailMessage(PathBuf, String, Value),
     RefreshChatMembers(String),
+    /// bytes transferred so far by a `PurpleXfer`.
+    XferProgress(Pointer, usize),
+    /// a `PurpleXfer` worker finished, with the error if it failed.
+    XferDone(Pointer, Option<String>),
+    /// `/recall` in the conversation with the given name finished.
+    RecallDone(String, Option<String>),
+    /// the friend request to the given user failed, with the error.
+    FriendRequestFailed(String, String),
+    /// the avatar of a contact or group, with its checksum.
+    SetIcon(String, Vec<u8>, String),
     YieldEvent,
 }
 
-pub fn send_server_message(m: SrvMsg) {
-    SRV_MSG.0.lock().unwrap().send(m).unwrap();
-}
-
@@ -1,535 +1,137 @@
 
 extern crate std;
-extern crate hyper;
-extern crate hyper_native_tls;
 extern crate regex;
-extern crate time;
 
-use self::hyper::Client;
-use self::hyper::header::{SetCookie, Cookie, Headers};
-use self::hyper::net::HttpsConnector;
-use self::hyper_native_tls::NativeTlsClient;
 use self::regex::Regex;
 use glib_sys;
 use libc;
 use user::User;
-use chatroom::ChatRoom;
+use chatroom::{ChatRoom, MemberUser};
 use serde_json::Value;
-use serde_json::Map;
 use pointer::*;
 use purple_sys::*;
 use message::*;
+use xfer;
+use icon;
+use icon::IconRequest;
+use appmsg;
+use appmsg::{AppMsg, Card, Location};
+use client::{Client, Event, Disconnect, FriendRequest, LoginError, Message, time_stamp};
+use transport::{HttpsTransport, Endpoints};
 use std::os::raw::{c_void, c_char, c_int};
 use std::io::*;
 use std::ffi::{CStr, CString};
 use std::ptr::null_mut;
-use std::sync::{RwLock, Mutex};
-use std::fs::{File, OpenOptions};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
 use std::thread;
-use std::fmt::Debug;
-use std::collections::BTreeSet;
-
-lazy_static!{
-    pub static ref ACCOUNT: RwLock<Pointer> = RwLock::new(Pointer::new());
-    static ref VERIFY_HANDLE: Mutex<Pointer> = Mutex::new(Pointer::new());
-    // static ref TX: Mutex<Cell<>> = Mutex::new(Cell::new(None));
-    // static ref CLT_MSG: (Mutex<Sender<CltMsg>>, Mutex<Receiver<CltMsg>>) =
-    //{let (tx, rx) = channel(); (Mutex::new(tx), Mutex::new(rx))};
-    static ref WECHAT: RwLock<WeChat> = RwLock::new(WeChat::new());
-    static ref CLIENT: Client = {
-        let ssl = NativeTlsClient::new().unwrap();
-        let connector = HttpsConnector::new(ssl);
-        Client::with_connector(connector)
-    };
-}
 
-// #[derive(Debug)]
-// pub enum CltMsg {
-// }
-
-struct WeChat {
-    uin: String,
-    sid: String,
-    skey: String,
-    device_id: String,
-    pass_ticket: String,
-    headers: Headers,
-    user_info: Value,
-    sync_keys: Value,
-
-    user_list: BTreeSet<User>,
-    chat_list: BTreeSet<ChatRoom>,
+const TRUE: i32 = 1;
+const FALSE: i32 = 0;
+
+/// account setting the session credentials are saved in.
+const SESSION_SETTING: &'static str = "session";
+/// how many received messages are remembered to show what was recalled.
+const MAX_INDEXED_MESSAGES: usize = 200;
+/// length of a message excerpt, in chars.
+const EXCERPT_LEN: usize = 50;
+
+/// state of one logged in account, attached to its `PurpleConnection` as
+/// protocol data between `login` and `close`.
+pub struct Connection {
+    gc: Pointer,
+    client: Arc<Client>,
+    events: Receiver<Event>,
+    srv_msg: (Sender<SrvMsg>, Receiver<SrvMsg>),
+    verify_handle: Cell<Pointer>,
+    check_srv_handle: Cell<u32>,
+    /// seconds since epoch when `login` was called.
+    login_time: i64,
+    /// `(MsgId, excerpt)` of the latest messages, oldest first.
+    messages: RefCell<VecDeque<(String, String)>>,
+    icons: Sender<IconRequest>,
+    /// buddy list entry of each group, by id.
+    blist_chats: RefCell<HashMap<String, Pointer>>,
 }
 
-unsafe impl std::marker::Sync for WeChat {}
-
-impl WeChat {
-    fn new() -> WeChat {
-        let mut headers = Headers::new();
-        headers.set_raw("Cookie", vec![vec![]]);
-        headers.set_raw("ContentType",
-                        vec![b"application/json; charset=UTF-8".to_vec()]);
-        headers.set_raw("Host", vec![b"web.wechat.com".to_vec()]);
-        headers.set_raw("Referer",
-                        vec![b"https://web.wechat.com/?&lang=zh_CN".to_vec()]);
-        headers.set_raw("Accept",
-                        vec![b"application/json, text/plain, */*".to_vec()]);
-
-        WeChat {
-            uin: String::new(),
-            sid: String::new(),
-            skey: String::new(),
-            device_id: format!("e56{}", time_stamp()),
-            pass_ticket: String::new(),
-            headers: headers,
-            user_info: Value::Null,
-            sync_keys: Value::Null,
-
-            user_list: BTreeSet::new(),
-            chat_list: BTreeSet::new(),
-        }
+impl Connection {
+    pub unsafe fn from_gc<'a>(gc: *mut PurpleConnection) -> Option<&'a Connection> {
+        let conn = purple_connection_get_protocol_data(gc) as *const Connection;
+        if conn.is_null() { None } else { Some(&*conn) }
     }
 
-    fn uin(&self) -> &str {
-        &self.uin
+    pub unsafe fn from_account<'a>(account: *mut PurpleAccount) -> Option<&'a Connection> {
+        Connection::from_gc(purple_account_get_connection(account))
     }
 
-    fn set_uin(&mut self, uin: &str) {
-        self.uin = uin.to_owned();
+    pub fn gc(&self) -> *mut PurpleConnection {
+        self.gc.as_ptr() as *mut PurpleConnection
     }
 
-    fn sid(&self) -> &str {
-        &self.sid
+    pub fn account(&self) -> *mut PurpleAccount {
+        unsafe { purple_connection_get_account(self.gc()) }
     }
 
-    fn set_sid(&mut self, sid: &str) {
-        self.sid = sid.to_owned();
+    pub fn client(&self) -> Arc<Client> {
+        self.client.clone()
     }
 
-    fn skey(&self) -> &str {
-        &self.skey
+    /// a sender for worker threads to post back to the main loop.
+    pub fn server_message_sender(&self) -> Sender<SrvMsg> {
+        self.srv_msg.0.clone()
     }
 
-    fn set_skey(&mut self, skey: &str) {
-        self.skey = skey.to_owned();
+    pub fn send_server_message(&self, m: SrvMsg) {
+        self.srv_msg.0.send(m).unwrap();
     }
 
-    fn device_id(&self) -> &str {
-        &self.device_id
+    /// the buddy list entry of the group `id`, or null.
+    pub fn blist_chat(&self, id: &str) -> *mut PurpleChat {
+        self.blist_chats
+            .borrow()
+            .get(id)
+            .map_or(null_mut(), |p| p.as_ptr() as *mut PurpleChat)
     }
 
-    fn pass_ticket(&self) -> &str {
-        &self.pass_ticket
+    fn set_blist_chat(&self, id: &str, chat: *mut PurpleChat) {
+        let ptr = Pointer::new().set(chat as *mut c_void);
+        self.blist_chats.borrow_mut().insert(id.to_owned(), ptr);
     }
 
-    fn set_pass_ticket(&mut self, pass_ticket: &str) {
-        self.pass_ticket = pass_ticket.to_owned();
+    fn take_blist_chat(&self, id: &str) -> *mut PurpleChat {
+        self.blist_chats
+            .borrow_mut()
+            .remove(id)
+            .map_or(null_mut(), |p| p.as_ptr() as *mut PurpleChat)
     }
 
-    fn sync_key_str(&self) -> String {
-
-        assert!(self.sync_keys.is_array());
-
-        let mut buf = String::new();
-        for item in self.sync_keys.as_array().unwrap() {
-            let k = item["Key"].as_i64().unwrap();
-            let v = item["Val"].as_i64().unwrap();
-
-            buf.push_str(&format!("{}_{}|", k, v));
+    /// fetch the avatar of a contact or group in the background.
+    fn request_icon(&self, name: &str, url: &str) {
+        if !url.is_empty() {
+            let _ = self.icons.send(IconRequest::new(name, url));
         }
-        buf.pop();
-
-        buf
     }
 
-    fn sync_key(&self) -> Value {
-        assert!(self.sync_keys.is_array());
-
-        let count = self.sync_keys.as_array().unwrap().len();
-        let value = json!({"Count" : count, "List" : self.sync_keys});
-
-        value
-    }
-
-    fn set_sync_key(&mut self, json: &Value) {
-        if let Value::Array(ref list) = json["List"] {
-            self.sync_keys = Value::Array(list.clone());
+    fn remember_message(&self, msg_id: &str, excerpt: String) {
+        let mut messages = self.messages.borrow_mut();
+        if messages.len() >= MAX_INDEXED_MESSAGES {
+            messages.pop_front();
         }
+        messages.push_back((msg_id.to_owned(), excerpt));
     }
 
-    fn set_user_info(&mut self, json: &Value) {
-        self.user_info = json["User"].clone()
+    fn find_message(&self, msg_id: &str) -> Option<String> {
+        self.messages
+            .borrow()
+            .iter()
+            .find(|&&(ref id, _)| id == msg_id)
+            .map(|&(_, ref excerpt)| excerpt.clone())
     }
-
-    fn user_name(&self) -> &str {
-        self.user_info["UserName"].as_str().unwrap()
-    }
-
-    fn set_cookies(&mut self, cookies: &SetCookie) {
-        println!("cookies: {:?}", cookies);
-        let ref mut jar = self.headers.get_mut::<Cookie>().unwrap();
-        for c in cookies.iter() {
-            let i = c.split(';').next().unwrap();
-            assert!(!i.is_empty());
-            jar.push(i.to_owned());
-        }
-
-        jar.remove(0);
-    }
-
-    fn headers(&self) -> Headers {
-        self.headers.clone()
-    }
-
-    fn append_user(&mut self, user: &User) {
-        if self.user_list.insert(user.clone()) {
-            send_server_message(SrvMsg::AddContact(user.clone()));
-        }
-    }
-
-    fn append_chat(&mut self, chat: &ChatRoom) {
-        if self.chat_list.insert(chat.clone()) {
-            send_server_message(SrvMsg::AddGroup(chat.clone()));
-        }
-    }
-
-    fn set_chat_ptr(&mut self, chat: &ChatRoom, chat_ptr: *mut PurpleChat) {
-        if let Some(mut c) = self.chat_list.take(chat) {
-            c.set_chat_ptr(chat_ptr as *mut c_void);
-            assert!(self.chat_list.insert(c));
-        } else {
-            println!("set chat ptr error, {:?}", chat);
-        }
-    }
-
-    fn find_chat_by_token(&self, token: usize) -> Option<&ChatRoom> {
-        for ref c in self.chat_list.iter() {
-            if c.token() == token {
-                return Some(c);
-            }
-        }
-
-        None
-    }
-
-    fn find_chat_by_id(&self, id: &str) -> Option<&ChatRoom> {
-        for ref c in self.chat_list.iter() {
-            if c.id() == id {
-                return Some(c);
-            }
-        }
-
-        None
-    }
-
-    fn find_chat_token(&self, id: &str) -> usize {
-
-        if let Some(c) = self.find_chat_by_id(id) {
-            return c.token();
-        }
-
-        0
-    }
-
-    fn find_chat_ptr(&self, id: &str) -> *mut PurpleChat {
-
-        if let Some(c) = self.find_chat_by_id(id) {
-            return c.chat_ptr() as *mut PurpleChat;
-        }
-
-        null_mut()
-    }
-
-    fn base_data(&self) -> Value {
-
-        let mut base_obj = Map::with_capacity(4);
-        base_obj.insert("Uin".to_owned(), json!(self.uin.parse::<usize>().unwrap()));
-        base_obj.insert("Sid".to_owned(), Value::String(self.sid.clone()));
-        base_obj.insert("Skey".to_owned(), Value::String(self.skey.clone()));
-        base_obj.insert("DeviceID".to_owned(), Value::String(self.device_id.clone()));
-
-        let mut obj = Map::new();
-        obj.insert("BaseRequest".to_owned(), Value::Object(base_obj));
-
-        Value::Object(obj)
-    }
-
-    fn status_notify_data(&self) -> Value {
-
-        let mut value = self.base_data();
-
-        value["Code"] = json!(3);
-        value["FromUserName"] = json!(self.user_name());
-        value["ToUserName"] = json!(self.user_name());
-        value["ClientMsgId"] = json!(time_stamp());
-
-        value
-    }
-
-    fn group_info_data(&self, groups: &[String]) -> Value {
-
-        let mut list = vec![];
-        for id in groups {
-            let item = json!({ "UserName": json!(id),
-                               "ChatRoomId": json!("") });
-            list.push(item);
-        }
-
-        let mut value = self.base_data();
-        value["Count"] = json!(groups.len());
-        value["List"] = json!(list);
-
-        value
-    }
-
-    fn message_check_data(&self) -> Value {
-
-        let mut value = self.base_data();
-
-        value["SyncKey"] = self.sync_key().clone();
-        value["rr"] = json!(!time_stamp());
-
-        value
-    }
-
-    fn message_send_data(&self, who: &str, content: &str) -> Value {
-
-        let mut id = time_stamp().to_string();
-        id.push_str("1234");
-
-        let msg = json!({
-            "Type" : 1,
-            "Content" : json!(content),
-            "FromUserName" : json!(self.user_name()),
-            "ToUserName" : json!(who),
-            "LocalID" : json!(id),
-            "ClientMsgId" : json!(id)
-        });
-
-        let mut value = self.base_data();
-        value["Msg"] = msg;
-        value["Scene"] = json!(0);
-
-        value
-    }
-}
-
-pub fn start_login() {
-
-    let uuid = get_uuid();
-    let url = format!("https://login.web.wechat.com/qrcode/{}", uuid);
-    let file_path = save_image(&url);
-    // let file_path = save_qr_file(&uuid);
-
-    // start check login thread
-    thread::spawn(|| { check_scan(uuid); });
-    send_server_message(SrvMsg::ShowVerifyImage(file_path));
-}
-
-fn check_scan(uuid: String) {
-    let url = format!("https://login.web.wechat.com/cgi-bin/mmwebwx-bin/login?uuid={}&tip={}",
-                      uuid,
-                      1);
-    // TODO: check result
-    let _ = get(&url);
-
-    let url = format!("https://login.web.wechat.com/cgi-bin/mmwebwx-bin/login?uuid={}&tip={}",
-                      uuid,
-                      0);
-
-    let result = get(&url);
-    let reg = Regex::new(r#"redirect_uri="([^"]+)""#).unwrap();
-    let caps = reg.captures(&result).unwrap();
-    let uri = caps.get(1).unwrap().as_str();
-
-    // scan successful, close dialog
-    let vh = VERIFY_HANDLE.lock().unwrap();
-    unsafe { purple_request_close(PURPLE_REQUEST_FIELDS, vh.as_ptr()) };
-
-    // webwxnewloginpage
-    let url = format!("{}&fun=new&version=v2", uri);
-    println!("login with: {}", url);
-    let mut response = CLIENT.get(&url).send().unwrap();
-    let mut result = String::new();
-    response.read_to_string(&mut result).unwrap();
-    println!("login result: {}", result);
-    let cookies = response.headers.get::<SetCookie>().unwrap();
-
-    let skey = regex_cap(&result, r#"<skey>(.*)</skey>"#);
-    let sid = regex_cap(&result, r#"<wxsid>(.*)</wxsid>"#);
-    let uin = regex_cap(&result, r#"<wxuin>(.*)</wxuin>"#);
-    let pass_ticket = regex_cap(&result, r#"<pass_ticket>(.*)</pass_ticket>"#);
-
-    {
-        let mut wechat = WECHAT.write().unwrap();
-        wechat.set_uin(&uin);
-        wechat.set_skey(&skey);
-        wechat.set_sid(&sid);
-        wechat.set_pass_ticket(&pass_ticket);
-        wechat.set_cookies(&cookies);
-    }
-
-    // init
-    let data = {
-        WECHAT.read().unwrap().base_data()
-    };
-    let url = format!("https://web.wechat.\
-                       com/cgi-bin/mmwebwx-bin/webwxinit?lang=zh_CN&pass_ticket={}&skey={}&r={}",
-                      pass_ticket,
-                      skey, time_stamp());
-    let json = post(&url, &data).parse::<Value>().unwrap();
-    println!("{}", json["BaseResponse"]);
-    {
-        let mut wechat = WECHAT.write().unwrap();
-        wechat.set_sync_key(&json["SyncKey"]);
-        wechat.set_user_info(&json);
-    }
-
-    let ref contact_list = json["ContactList"].as_array().unwrap();
-    let mut groups = vec![];
-    for contact in *contact_list {
-        let name = contact["UserName"].as_str().unwrap();
-        if name.starts_with("@@") {
-            groups.push(name.to_owned());
-        }
-    }
-
-    let (url, data) = {
-        let wechat = WECHAT.read().unwrap();
-        let url = format!("https://web.wechat.com/cgi-bin/mmwebwx-bin/\
-                           webwxbatchgetcontact?type=ex&r={}&pass_ticket={}",
-        time_stamp(), wechat.pass_ticket());
-        let data = wechat.group_info_data(&groups[..]);
-
-        (url, data)
-    };
-    let result = post(&url, &data);
-    let json = result.parse::<Value>().unwrap();
-    let ref groups = json["ContactList"].as_array().unwrap();
-    if groups.len() != 0 {
-        let mut wechat = WECHAT.write().unwrap();
-        for group in *groups {
-            wechat.append_chat(&ChatRoom::from_json(group));
-        }
-    }
-
-    // fetch contact list
-    thread::spawn(|| fetch_contact());
-
-    // refersh current user name
-    unsafe {
-        let uname = CString::new(WECHAT.read().unwrap().user_name()).unwrap();
-        let alias = CString::new("You").unwrap();
-        println!("set usernmae: {:?}", uname);
-        purple_account_set_username(ACCOUNT.read().unwrap().as_ptr() as *mut PurpleAccount,
-                                    uname.as_ptr());
-        purple_account_set_alias(ACCOUNT.read().unwrap().as_ptr() as *mut PurpleAccount,
-                                 alias.as_ptr());
-    }
-
-    // status notify
-    let url = format!("https://web.wechat.\
-                       com/cgi-bin/mmwebwx-bin/webwxstatusnotify?lang=zh_CN&pass_ticket={}",
-                      pass_ticket);
-    let data = WECHAT.read().unwrap().status_notify_data();
-    // TODO: check result
-    let _ = post(&url, &data);
-
-    // start message check loop
-    thread::spawn(|| sync_check());
-}
-
-fn time_stamp() -> i64 {
-    time::get_time().sec * 1000
-}
-
-fn fetch_contact() {
-    let url = {
-        let wechat = WECHAT.read().unwrap();
-        format!("https://web.wechat.com/cgi-bin/mmwebwx-bin/\
-                 webwxgetcontact?pass_ticket={}&skey={}&r={}&seq=0",
-        wechat.pass_ticket(), wechat.skey(), time_stamp())
-    };
-
-    let result = get(url).parse::<Value>().unwrap();
-    let ref member_list = result["MemberList"].as_array().unwrap();
-
-    // yield event loop
-    {
-        send_server_message(SrvMsg::YieldEvent);
-    }
-
-    let mut wechat = WECHAT.write().unwrap();
-    for member in *member_list {
-        wechat.append_user(&User::from_json(member));
-    }
-}
-
-fn sync_check() {
-
-    let mut headers = Headers::new();
-    {
-        let hrs = WECHAT.read().unwrap().headers();
-        println!("{:?}", hrs);
-        headers.set(hrs.get::<Cookie>().unwrap().clone());
-        headers.set_raw("Host", vec![b"webpush.web.wechat.com".to_vec()]);
-        headers.set_raw("Accept", vec![b"*/*".to_vec()]);
-        headers.set_raw("Referer",
-                        vec![b"https://webpush.web.wechat.com/?&lang=zh_CN".to_vec()]);
-    }
-
-    println!("{:?}", headers);
-
-    // let uid
-    loop {
-        let url = {
-            let wechat = WECHAT.read().unwrap();
-            let ts = time_stamp();
-            format!("https://webpush.web.wechat.com/cgi-bin/mmwebwx-bin/synccheck\
-            ?sid={}&uin={}&skey={}&deviceid={}&synckey={}&r={}&_={}",
-                    wechat.sid(),
-                    wechat.uin(),
-                    wechat.skey(),
-                    wechat.device_id(),
-                    wechat.sync_key_str(),
-                    ts,
-                    ts)
-        };
-
-        println!("sync check url: {}", url);
-
-        let mut response = CLIENT
-            .get(&url)
-            .headers(headers.clone())
-            .send()
-            .unwrap();
-        let mut result = String::new();
-        response.read_to_string(&mut result).unwrap();
-
-        let reg = Regex::new(r#"retcode:"(\d+)",selector:"(\d+)""#).unwrap();
-        let caps = reg.captures(&result).unwrap();
-        let retcode: isize = caps.get(1).unwrap().as_str().parse().unwrap();
-        let selector: isize = caps.get(2).unwrap().as_str().parse().unwrap();
-        println!("{} = {} - {}", result, retcode, selector);
-
-        // logout
-        if retcode == 1100 || retcode == 1101 {
-            break;
-        }
-
-        // no new message.
-        if selector == 0 {
-            continue;
-        }
-
-        check_new_message();
-    }
-
-    // show logout message
-    let m = "You are already logged in other devices.\nplease logout and restart pidgin."
-        .to_owned();
-    let m = SrvMsg::ShowMessageBox(m);
-    send_server_message(m);
 }
 
 unsafe fn show_message_box(message: &str) {
@@ -561,29 +163,33 @@ unsafe fn show_message_box(message: &str) {
                           null_mut()); // user_data
 }
 
-pub unsafe extern "C" fn send_chat(_: *mut PurpleConnection,
+pub unsafe extern "C" fn send_chat(gc: *mut PurpleConnection,
                                    id: i32,
                                    msg: *const c_char,
                                    _: PurpleMessageFlags)
                                    -> c_int {
 
+    let conn = match Connection::from_gc(gc) {
+        Some(conn) => conn,
+        None => return -1,
+    };
     let msg_cstr = CStr::from_ptr(msg).to_string_lossy().into_owned();
 
-    let wechat = WECHAT.read().unwrap();
-    if let Some(chat) = wechat.find_chat_by_token(id as usize) {
+    if let Some(chat) = conn.client().find_chat_by_token(id as usize) {
 
         let chat_id = chat.id();
-        let conv = conversion(PURPLE_CONV_TYPE_CHAT, &chat_id);
+        let conv = conversion(conn, PURPLE_CONV_TYPE_CHAT, &chat_id);
         let chat = purple_conversation_get_chat_data(conv);
-        let self_name = CString::new(wechat.user_name()).unwrap();
+        let self_name = CString::new(conn.client().user_name()).unwrap();
+        let flags = if msg_cstr.contains("<IMG ID=") {
+            PURPLE_MESSAGE_SEND | PURPLE_MESSAGE_IMAGES
+        } else {
+            PURPLE_MESSAGE_SEND
+        };
 
-        purple_conv_chat_write(chat,
-                               self_name.as_ptr(),
-                               msg,
-                               PURPLE_MESSAGE_SEND,
-                               time_stamp() / 1000);
+        purple_conv_chat_write(chat, self_name.as_ptr(), msg, flags, time_stamp() / 1000);
 
-        send_message(&chat_id, &msg_cstr);
+        send_message(conn, &chat_id, &msg_cstr);
     } else {
         println!("chat not found {}", msg_cstr);
     }
@@ -591,144 +197,237 @@ pub unsafe extern "C" fn send_chat(_: *mut PurpleConnection,
     0
 }
 
-fn send_message(who: &str, msg: &str) {
+unsafe fn send_message(conn: &Connection, who: &str, msg: &str) {
 
-    println!("send_message: {}: {}", who, msg);
+    let client = conn.client();
+    let who = who.to_owned();
+    let (msg, images) = take_images(msg);
 
-    let (url, data) = {
-        let wechat = WECHAT.read().unwrap();
-        let url = format!("https://web.wechat.com/cgi-bin/mmwebwx-bin/webwxsendmsg?\
-                           pass_ticket={}", wechat.pass_ticket());
-        let data = wechat.message_send_data(who, msg);
+    thread::spawn(move || {
+        for (file_name, data) in images {
+            if let Err(e) = client.send_image(&who, &file_name, &data) {
+                println!("send image failed: {}", e);
+            }
+        }
 
-        (url, data)
-    };
+        if msg.is_empty() {
+            return;
+        }
+
+        if let Err(e) = client.send_message(&who, &msg) {
+            println!("send message failed: {}", e);
+        }
+    });
+}
+
+/// remove images inserted from the imgstore (`<IMG ID="n">`) from an outgoing
+/// message, returns the remaining text and the name and data of each image.
+unsafe fn take_images(msg: &str) -> (String, Vec<(String, Vec<u8>)>) {
 
-    // TODO: check result.
-    thread::spawn(move || { let _ = post(&url, &data); });
+    let regex = Regex::new(r#"(?i)<img id="(\d+)"[^>]*>"#).unwrap();
+
+    let mut images = vec![];
+    for caps in regex.captures_iter(msg) {
+        let id = caps.get(1).unwrap().as_str().parse::<i32>().unwrap();
+        let img = purple_imgstore_find_by_id(id);
+        if img == null_mut() {
+            println!("image {} not found", id);
+            continue;
+        }
+
+        let size = purple_imgstore_get_size(img) as usize;
+        let data = std::slice::from_raw_parts(purple_imgstore_get_data(img) as *const u8, size);
+
+        let file_name = purple_imgstore_get_filename(img);
+        let file_name = if file_name != null_mut() {
+            CStr::from_ptr(file_name).to_string_lossy().into_owned()
+        } else {
+            let ext = CStr::from_ptr(purple_imgstore_get_extension(img));
+            format!("image.{}", ext.to_string_lossy())
+        };
+
+        images.push((file_name, data.to_vec()));
+    }
+
+    let text = regex.replace_all(msg, "").trim().to_owned();
+
+    (text, images)
 }
 
-pub unsafe extern "C" fn send_im(_: *mut PurpleConnection,
+pub unsafe extern "C" fn send_im(gc: *mut PurpleConnection,
                                  who: *const c_char,
                                  msg: *const c_char,
                                  _: PurpleMessageFlags)
                                  -> c_int {
 
+    let conn = match Connection::from_gc(gc) {
+        Some(conn) => conn,
+        None => return -1,
+    };
+
     let who = CStr::from_ptr(who).to_string_lossy().into_owned();
     let msg = CStr::from_ptr(msg).to_string_lossy().into_owned();
 
-    send_message(&who, &msg);
+    send_message(conn, &who, &msg);
 
     1
 }
 
-fn check_new_message() {
+pub unsafe extern "C" fn add_friend(gc: *mut PurpleConnection,
+                                    buddy: *mut PurpleBuddy,
+                                    group: *mut PurpleGroup) {
+    add_friend_with_invite(gc, buddy, group, null_mut());
+}
 
-    let (url, data) = {
-        let wechat = WECHAT.read().unwrap();
-        let url = format!("https://web.wechat.\
-                       com/cgi-bin/mmwebwx-bin/webwxsync?sid={}&skey={}&pass_ticket={}",
-                      wechat.sid(),
-                      wechat.skey(),
-                      wechat.pass_ticket());
+/// send a friend request to a group member or a searched contact, the buddy
+/// is filled in by `ContactAdded` once the request is accepted.
+pub unsafe extern "C" fn add_friend_with_invite(gc: *mut PurpleConnection,
+                                                buddy: *mut PurpleBuddy,
+                                                _: *mut PurpleGroup,
+                                                message: *const c_char) {
 
-        (url, wechat.message_check_data())
+    let conn = match Connection::from_gc(gc) {
+        Some(conn) => conn,
+        None => return,
     };
 
-    let result = post(&url, &data);
-
-    // refersh sync check key
-    let json: Value = result.parse().unwrap();
-    {
-        WECHAT
-            .write()
-            .unwrap()
-            .set_sync_key(&json["SyncCheckKey"]);
+    let who = CStr::from_ptr(purple_buddy_get_name(buddy)).to_string_lossy().into_owned();
+    let client = conn.client();
+    if client.is_contact(&who) {
+        return;
     }
 
-    send_server_message(SrvMsg::MessageReceived(json));
-}
+    // user names are only valid for this session
+    purple_blist_node_set_flags(buddy as *mut PurpleBlistNode, PURPLE_BLIST_NODE_FLAG_NO_SAVE);
 
-fn regex_cap<'a>(c: &'a str, r: &str) -> &'a str {
-    let reg = Regex::new(r).unwrap();
-    let caps = reg.captures(&c).unwrap();
+    let message = if message != null_mut() && *message != 0 {
+        CStr::from_ptr(message).to_string_lossy().into_owned()
+    } else {
+        format!("I'm {}", client.nick_name())
+    };
 
-    caps.get(1).unwrap().as_str()
+    let sender = conn.server_message_sender();
+    thread::spawn(move || {
+        let result = match client.add_friend(&who, &message) {
+            Ok(()) => SrvMsg::ShowMessageBox(format!("Friend request sent to {}.", who)),
+            Err(e) => {
+                println!("add friend failed: {}", e);
+                SrvMsg::FriendRequestFailed(who, format!("{}", e))
+            }
+        };
+
+        let _ = sender.send(result);
+    });
 }
 
-fn get_uuid() -> String {
-    let url = "https://login.web.wechat.com/jslogin?appid=wx782c26e4c19acffb&redirect_uri=\
-               https://web.wechat.com/cgi-bin/mmwebwx-bin/webwxnewloginpage&fun=new&lang=zh_CN";
-    let result = get(&url);
+/// drop the buddy libpurple added for a friend request that was not sent.
+unsafe fn friend_request_failed(conn: &Connection, who: &str, error: &str) {
 
-    let reg = Regex::new(r#"uuid\s*=\s*"([-\w=]+)""#).unwrap();
-    let caps = reg.captures(&result).unwrap();
+    if !conn.client().is_contact(who) {
+        remove_buddy(conn, who);
+    }
 
-    caps.get(1).unwrap().as_str().to_owned()
+    show_message_box(&format!("Could not send the friend request to {}: {}", who, error));
 }
 
-fn get<T: AsRef<str> + Debug>(url: T) -> String {
+/// `/recall`, revoke the last message we sent in a conversation.
+pub unsafe extern "C" fn recall_cmd(conv: *mut PurpleConversation,
+                                    _: *const c_char,
+                                    _: *mut *mut c_char,
+                                    error: *mut *mut c_char,
+                                    _: *mut c_void)
+                                    -> PurpleCmdRet {
+
+    let conn = match Connection::from_account(purple_conversation_get_account(conv)) {
+        Some(conn) => conn,
+        None => return PURPLE_CMD_RET_FAILED,
+    };
+
+    let who = CStr::from_ptr(purple_conversation_get_name(conv)).to_string_lossy().into_owned();
+    let client = conn.client();
 
-    let headers = {
-        WECHAT.read().unwrap().headers()
+    let sent = match client.revocable(&who) {
+        Ok(sent) => sent,
+        Err(e) => {
+            let message = CString::new(format!("{}", e)).unwrap();
+            *error = g_strdup(message.as_ptr());
+            return PURPLE_CMD_RET_FAILED;
+        }
     };
 
-    println!("get: {:?}", url);
-    let mut response = CLIENT
-        .get(url.as_ref())
-        .headers(headers)
-        .send()
-        .unwrap();
-    let mut result = String::new();
-    response.read_to_string(&mut result).unwrap();
-    if result.len() > 500 {
-        println!("result: {}", &result[0..300]);
-    } else {
-        println!("result: {}", result);
-    }
+    let sender = conn.server_message_sender();
+    thread::spawn(move || {
+        let result = client.revoke(&sent).map_err(|e| format!("{}", e));
+        let _ = sender.send(SrvMsg::RecallDone(who, result.err()));
+    });
 
-    result
+    PURPLE_CMD_RET_OK
 }
 
-fn post<U: AsRef<str> + Debug>(url: U, data: &Value) -> String {
+unsafe fn recall_done(conn: &Connection, who: &str, error: Option<String>) {
 
-    let headers = {
-        WECHAT.read().unwrap().headers()
+    let (text, flags) = match error {
+        None => ("You recalled a message.".to_owned(), PURPLE_MESSAGE_SYSTEM),
+        Some(e) => {
+            println!("recall failed: {}", e);
+            (format!("Recall failed: {}", e), PURPLE_MESSAGE_ERROR)
+        }
     };
-    println!("post: {:?}\nheaders:{:?}\npost_data: {:?}",
-             url,
-             headers,
-             data);
-    let mut response = CLIENT
-        .post(url.as_ref())
-        .headers(headers)
-        .body(&data.to_string())
-        .send()
-        .unwrap();
-    let mut result = String::new();
-    response.read_to_string(&mut result).unwrap();
-    if result.len() > 500 {
-        println!("result: {}", &result[0..300]);
+
+    let conv_type = if who.starts_with("@@") {
+        PURPLE_CONV_TYPE_CHAT
     } else {
-        println!("result: {}", result);
-    }
+        PURPLE_CONV_TYPE_IM
+    };
+    let conv = conversion(conn, conv_type, who);
+    let text = CString::new(text).unwrap();
+    let empty = CString::new("").unwrap();
 
-    result
+    purple_conversation_write(conv, empty.as_ptr(), text.as_ptr(), flags, time_stamp() / 1000);
 }
 
-unsafe extern "C" fn check_srv(_: *mut c_void) -> c_int {
+unsafe extern "C" fn check_srv(gc: *mut c_void) -> c_int {
+
+    let conn = match Connection::from_gc(gc as *mut PurpleConnection) {
+        Some(conn) => conn,
+        None => return 0,
+    };
 
-    let rx = SRV_MSG.1.lock().unwrap();
+    while let Ok(e) = conn.events.try_recv() {
+        match e {
+            Event::QrCode(image) => show_verify_image(conn, &image),
+            Event::Scanned(avatar) => {
+                show_confirm_dialog(conn, avatar.as_ref().map(|a| &a[..]))
+            }
+            Event::Confirmed => close_verify_image(conn),
+            Event::LoginFailed(e) => login_failed(conn, &e),
+            Event::LoggedIn(user_name) => logged_in(conn, &user_name),
+            Event::SessionUpdated(session) => save_session(conn, &session),
+            Event::ContactAdded(user) => add_buddy(conn, &user),
+            Event::ContactUpdated(user) => update_buddy(conn, &user),
+            Event::ContactRemoved(user_name) => remove_buddy(conn, &user_name),
+            Event::GroupAdded(chat) => add_group(conn, &chat),
+            Event::GroupUpdated(chat, joined, left) => update_group(conn, &chat, &joined, &left),
+            Event::GroupRemoved(chat) => remove_group(conn, &chat),
+            Event::Message(msg) => append_message(conn, &msg),
+            Event::Disconnected(reason) => disconnected(conn, &reason),
+        }
+    }
 
-    while let Ok(m) = rx.try_recv() {
+    while let Ok(m) = conn.srv_msg.1.try_recv() {
         match m {
             SrvMsg::ShowMessageBox(m) => show_message_box(&m),
-            SrvMsg::ShowVerifyImage(path) => show_verify_image(path),
-            SrvMsg::AddContact(user) => add_buddy(&user),
-            SrvMsg::AddGroup(chat) => add_group(&chat),
-            SrvMsg::MessageReceived(json) => append_message(&json),
-            SrvMsg::AppendImageMessage(id, json) => append_image_message(id, &json),
-            SrvMsg::RefreshChatMembers(chat) => refresh_chat_members(&chat),
+            SrvMsg::AppendImageMessage(image, json) => append_image_message(conn, &image, &json),
+            SrvMsg::AppendMediaMessage(html, json) => append_media_message(conn, &html, &json),
+            SrvMsg::AppendThumbnailMessage(thumb, html, json) => {
+                append_thumbnail_message(conn, &thumb, &html, &json)
+            }
+            SrvMsg::RefreshChatMembers(chat) => refresh_chat_members(conn, &chat),
+            SrvMsg::XferProgress(xfer, bytes) => xfer::progress(xfer, bytes),
+            SrvMsg::XferDone(xfer, error) => xfer::done(xfer, error),
+            SrvMsg::RecallDone(who, error) => recall_done(conn, &who, error),
+            SrvMsg::FriendRequestFailed(who, e) => friend_request_failed(conn, &who, &e),
+            SrvMsg::SetIcon(name, data, checksum) => icon::set_icon(conn, &name, &data, &checksum),
             SrvMsg::YieldEvent => break,
         }
     }
@@ -736,10 +435,13 @@ unsafe extern "C" fn check_srv(_: *mut c_void) -> c_int {
     1
 }
 
-unsafe fn refresh_chat_members(chat: &str) {
-    let wechat = WECHAT.read().unwrap();
-    let chat = wechat.find_chat_by_id(chat).unwrap();
-    let conv = conversion(PURPLE_CONV_TYPE_CHAT, &chat.id());
+unsafe fn refresh_chat_members(conn: &Connection, chat: &str) {
+    // the group may be gone by now
+    let chat = match conn.client().find_chat_by_id(chat) {
+        Some(chat) => chat,
+        None => return,
+    };
+    let conv = conversion(conn, PURPLE_CONV_TYPE_CHAT, &chat.id());
     let conv_chat = purple_conversation_get_chat_data(conv);
 
     for member in chat.members() {
@@ -748,7 +450,7 @@ unsafe fn refresh_chat_members(chat: &str) {
     }
 }
 
-unsafe fn add_group(chat: &ChatRoom) {
+unsafe fn add_group(conn: &Connection, chat: &ChatRoom) {
 
     println!("add group: {} {}", chat.alias(), chat.token());
 
@@ -768,14 +470,12 @@ unsafe fn add_group(chat: &ChatRoom) {
                         g_strdup(id.as_ptr()) as *mut c_void);
 
     let account = {
-        ACCOUNT.read().unwrap().as_ptr() as *mut PurpleAccount
+        conn.account()
     };
 
     let chat_ptr = purple_chat_new(account as *mut PurpleAccount, id.as_ptr(), hash_table);
 
-    {
-        WECHAT.write().unwrap().set_chat_ptr(chat, chat_ptr);
-    }
+    conn.set_blist_chat(&chat.id(), chat_ptr);
 
     let group_name = CString::new("Wechat Groups").unwrap();
     let group = purple_find_group(group_name.as_ptr());
@@ -788,30 +488,79 @@ unsafe fn add_group(chat: &ChatRoom) {
         let alias = chat.alias_cstring();
         purple_blist_alias_chat(chat_ptr, alias.as_ptr());
     }
+
+    conn.request_icon(&chat.id(), &chat.head_img_url());
+}
+
+unsafe fn update_group(conn: &Connection,
+                       chat: &ChatRoom,
+                       joined: &[MemberUser],
+                       left: &[MemberUser]) {
+
+    println!("update group: {} +{} -{}", chat.alias(), joined.len(), left.len());
+
+    let chat_ptr = conn.blist_chat(&chat.id());
+    if chat_ptr != null_mut() && !chat.alias().is_empty() {
+        let alias = chat.alias_cstring();
+        purple_blist_alias_chat(chat_ptr, alias.as_ptr());
+    }
+    conn.request_icon(&chat.id(), &chat.head_img_url());
+
+    // only an open conversation shows the membership change
+    let id = chat.id_cstring();
+    let conv = purple_find_conversation_with_account(PURPLE_CONV_TYPE_CHAT,
+                                                     id.as_ptr(),
+                                                     conn.account());
+    if conv == null_mut() {
+        return;
+    }
+
+    let conv_chat = purple_conversation_get_chat_data(conv);
+    for member in joined {
+        let id = CString::new(member.user_name()).unwrap();
+        purple_conv_chat_add_user(conv_chat, id.as_ptr(), null_mut(), PURPLE_CBFLAGS_NONE, TRUE);
+    }
+    for member in left {
+        let id = CString::new(member.user_name()).unwrap();
+        purple_conv_chat_remove_user(conv_chat, id.as_ptr(), null_mut());
+    }
 }
 
-pub unsafe extern "C" fn find_blist_chat(_: *mut PurpleAccount,
+unsafe fn remove_group(conn: &Connection, chat: &ChatRoom) {
+
+    println!("remove group: {}", chat.alias());
+
+    let chat_ptr = conn.take_blist_chat(&chat.id());
+    if chat_ptr != null_mut() {
+        purple_blist_remove_chat(chat_ptr);
+    }
+}
+
+pub unsafe extern "C" fn find_blist_chat(account: *mut PurpleAccount,
                                          name: *const c_char)
                                          -> *mut PurpleChat {
+    let conn = match Connection::from_account(account) {
+        Some(conn) => conn,
+        None => return null_mut(),
+    };
     let name = CStr::from_ptr(name);
 
-    let chat_ptr = WECHAT
-        .read()
-        .unwrap()
-        .find_chat_ptr(name.to_string_lossy().to_mut());
-
-    chat_ptr
+    conn.blist_chat(name.to_string_lossy().as_ref())
 }
 
-pub fn find_chat_token(id: &str) -> usize {
-    let token = WECHAT.read().unwrap().find_chat_token(id);
-
-    token
+pub fn find_chat_token(conn: &Connection, id: &str) -> usize {
+    match conn.client().find_chat_by_id(id) {
+        Some(c) => c.token(),
+        None => 0,
+    }
 }
 
-unsafe fn conversion(conv_type: PurpleConversationType, name: &str) -> *mut PurpleConversation {
+unsafe fn conversion(conn: &Connection,
+                     conv_type: PurpleConversationType,
+                     name: &str)
+                     -> *mut PurpleConversation {
     let name_cstr = CString::new(name).unwrap();
-    let account = ACCOUNT.read().unwrap().as_ptr() as *mut PurpleAccount;
+    let account = conn.account();
     let conv = purple_find_conversation_with_account(conv_type, name_cstr.as_ptr(), account);
 
     if conv != null_mut() {
@@ -824,7 +573,7 @@ unsafe fn conversion(conv_type: PurpleConversationType, name: &str) -> *mut Purp
     }
 
     assert!(name.starts_with("@@"));
-    let token = find_chat_token(name);
+    let token = find_chat_token(conn, name);
 
     // search chat
     let gc = (*account).gc;
@@ -835,7 +584,6 @@ unsafe fn conversion(conv_type: PurpleConversationType, name: &str) -> *mut Purp
     }
 
     // join chat
-    let account = ACCOUNT.read().unwrap().as_ptr() as *mut PurpleAccount;
     println!("join chat {:?}, token = {}", name, token);
     serv_got_joined_chat(purple_account_get_connection(account),
                          token as i32,
@@ -846,124 +594,222 @@ unsafe fn conversion(conv_type: PurpleConversationType, name: &str) -> *mut Purp
     // ensure not nullptr
     assert!(conv != null_mut());
     // add members
-    send_server_message(SrvMsg::YieldEvent);
-    send_server_message(SrvMsg::RefreshChatMembers(name.to_owned()));
+    conn.send_server_message(SrvMsg::YieldEvent);
+    conn.send_server_message(SrvMsg::RefreshChatMembers(name.to_owned()));
 
     conv
 }
 
-fn save_image(url: &str) -> String {
+/// directory downloaded media is kept in, created on demand.
+unsafe fn media_cache_dir() -> PathBuf {
 
-    let headers = {
-        WECHAT.read().unwrap().headers()
-    };
+    let dir = Path::new(CStr::from_ptr(purple_user_dir()).to_string_lossy().as_ref())
+        .join("wechat")
+        .join("media");
+    if let Err(e) = fs::create_dir_all(&dir) {
+        println!("create media cache {:?} failed: {}", dir, e);
+    }
+
+    dir
+}
 
-    let mut response = CLIENT.get(url).headers(headers).send().unwrap();
-    let mut result = Vec::new();
-    response.read_to_end(&mut result).unwrap();
+unsafe fn append_image_message(conn: &Connection, image: &[u8], msg: &Value) {
 
-    println!("fetched image: {} {} {}", url, response.status, result.len());
+    // the imgstore takes ownership of the copy
+    let data = g_memdup(image.as_ptr() as *const c_void, image.len() as _);
+    let id = purple_imgstore_add_with_id(data, image.len() as _, null_mut());
+    let img_msg = format!(r#"<IMG ID="{}">"#, id);
 
-    save_file(&result)
+    append_media_message(conn, &img_msg, msg);
 }
 
-fn save_file(buf: &[u8]) -> String {
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open("/tmp/img.jpg")
-        .unwrap();
-    file.write_all(buf).unwrap();
+unsafe fn append_thumbnail_message(conn: &Connection, thumb: &Path, html: &str, msg: &Value) {
+
+    let path = CString::new(thumb.to_string_lossy().as_ref()).unwrap();
+    let img = purple_imgstore_new_from_file(path.as_ptr());
+    if img == null_mut() {
+        return append_media_message(conn, html, msg);
+    }
+
+    let img_data = purple_imgstore_get_data(img);
+    let img_size = purple_imgstore_get_size(img);
+    let img_filename = purple_imgstore_get_filename(img);
+    let id = purple_imgstore_add_with_id(img_data as *mut c_void, img_size, img_filename);
 
-    "/tmp/img.jpg".to_owned()
+    append_media_message(conn, &format!(r#"<IMG ID="{}"><br/>{}"#, id, html), msg);
 }
 
-fn append_image_message(id: i32, msg: &Value) {
+/// write `html` in place of the content of `msg`.
+fn append_media_message(conn: &Connection, html: &str, msg: &Value) {
 
     let src = msg["FromUserName"].as_str().unwrap();
     let dest = msg["ToUserName"].as_str().unwrap();
     let time = msg["CreateTime"].as_i64().unwrap();
-    let img_msg = format!(r#"<IMG ID="{}">"#, id);
 
     if src.starts_with("@@") {
         let content = msg["Content"].as_str().unwrap();
 
         // split content to find real sender
-        let regex = Regex::new(r#"^(@\w+):.*$"#).unwrap();
-        let caps = regex.captures(content).unwrap();
-        let sender = caps.get(1).unwrap().as_str();
-
-        append_purple_chat_message(src, dest, sender, &img_msg, time);
+        let regex = Regex::new(r#"(?s)^(@\w+):.*$"#).unwrap();
+        match regex.captures(content).and_then(|caps| caps.get(1)) {
+            Some(sender) => {
+                append_purple_chat_message(conn, src, dest, sender.as_str(), html, time)
+            }
+            None => append_purple_chat_notice(conn, src, html, time),
+        }
     } else if dest.starts_with("@@") {
-        append_purple_chat_message(src, dest, dest, &img_msg, time);
+        append_purple_chat_message(conn, src, dest, dest, html, time);
     } else {
-        append_purple_im_message(src, dest, &img_msg, time);
+        append_purple_im_message(conn, src, dest, html, time);
     }
 }
 
-unsafe fn process_image_message(msg: &Value) {
-
-    let msg_id = msg["MsgId"].as_str().unwrap();
-    let url = format!("https://web.wechat.com/cgi-bin/mmwebwx-bin/webwxgetmsgimg?&MsgID={}&skey={}",
-                       msg_id, WECHAT.read().unwrap().skey());
+unsafe fn process_image_message(conn: &Connection, msg: &Value) {
 
+    let msg_id = msg["MsgId"].as_str().unwrap().to_owned();
+    let client = conn.client();
+    let sender = conn.server_message_sender();
     let msg = msg.clone();
 
     thread::spawn(move || {
-        let img_path = CString::new(save_image(&url)).unwrap();
-        let img = purple_imgstore_new_from_file(img_path.as_ptr());
-        let img_data = purple_imgstore_get_data(img);
-        let img_size = purple_imgstore_get_size(img);
-        let img_filename = purple_imgstore_get_filename(img);
-
-        let id = purple_imgstore_add_with_id(img_data as *mut c_void, img_size, img_filename);
+        let image = match client.get_msg_image(&msg_id) {
+            Ok(image) => image,
+            Err(e) => return println!("fetch image failed: {}", e),
+        };
 
-        let sender = SRV_MSG.0.lock().unwrap();
-        sender.send(SrvMsg::YieldEvent).unwrap();
-        sender.send(SrvMsg::AppendImageMessage(id, msg)).unwrap()
+        let _ = sender.send(SrvMsg::YieldEvent);
+        let _ = sender.send(SrvMsg::AppendImageMessage(image, msg));
     });
 }
 
-unsafe fn process_emoji_image(msg: &Value) {
+unsafe fn process_emoji_image(conn: &Connection, msg: &Value) {
 
     let content = msg["Content"].as_str().unwrap();
     let regex = Regex::new(r#"cdnurl\s*=\s*"([^"]+)""#).unwrap();
 
     let caps = match regex.captures(content) {
         Some(caps) => caps,
-        None => return append_text_message(msg),
+        None => return append_text_message(conn, msg),
     };
 
     let url = caps.get(1).unwrap().as_str().to_owned();
+    let client = conn.client();
+    let sender = conn.server_message_sender();
+    let msg = msg.clone();
+
+    thread::spawn(move || {
+
+        let image = match client.download(&url) {
+            Ok(image) => image,
+            Err(e) => return println!("fetch image failed: {}", e),
+        };
+
+        let _ = sender.send(SrvMsg::YieldEvent);
+        let _ = sender.send(SrvMsg::AppendImageMessage(image, msg));
+    });
+}
+
+unsafe fn process_voice_message(conn: &Connection, msg: &Value) {
+
+    let msg_id = msg["MsgId"].as_str().unwrap().to_owned();
+    let length = msg["VoiceLength"].as_i64().unwrap_or(0);
+    let path = media_cache_dir().join(format!("voice-{}.mp3", msg_id));
+    let client = conn.client();
+    let sender = conn.server_message_sender();
     let msg = msg.clone();
 
     thread::spawn(move || {
+        let voice = match client.get_voice(&msg_id) {
+            Ok(voice) => voice,
+            Err(e) => return println!("fetch voice failed: {}", e),
+        };
+
+        if let Err(e) = File::create(&path).and_then(|mut f| f.write_all(&voice)) {
+            return println!("save voice {:?} failed: {}", path, e);
+        }
+
+        let html = format!(r#"[Voice {:.1}s] <a href="file://{}">{}</a>"#,
+                           length as f64 / 1000.0,
+                           path.display(),
+                           path.file_name().unwrap().to_string_lossy());
+
+        let _ = sender.send(SrvMsg::YieldEvent);
+        let _ = sender.send(SrvMsg::AppendMediaMessage(html, msg));
+    });
+}
 
-        let mut headers = Headers::new();
-        headers.set_raw("Host", vec![b"emoji.qpic.cn".to_vec()]);
+unsafe fn process_video_message(conn: &Connection, msg: &Value) {
 
-        let mut response = CLIENT.get(&url).headers(headers).send().unwrap();
-        let mut result = Vec::new();
-        response.read_to_end(&mut result).unwrap();
+    let msg_id = msg["MsgId"].as_str().unwrap().to_owned();
+    let length = msg["PlayLength"].as_i64().unwrap_or(0);
+    let cache = media_cache_dir();
+    let thumb_path = cache.join(format!("video-{}.jpg", msg_id));
+    let video_path = cache.join(format!("video-{}.mp4", msg_id));
+    let client = conn.client();
+    let sender = conn.server_message_sender();
+    let msg = msg.clone();
 
-        println!("fetch image: {} {} {}", url, response.status, result.len());
+    thread::spawn(move || {
+        let has_thumb = match client.get_video_thumbnail(&msg_id) {
+            Ok(thumb) => File::create(&thumb_path).and_then(|mut f| f.write_all(&thumb)).is_ok(),
+            Err(e) => {
+                println!("fetch video thumbnail failed: {}", e);
+                false
+            }
+        };
 
-        let img_path = CString::new(save_file(&result)).unwrap();
-        let img = purple_imgstore_new_from_file(img_path.as_ptr());
-        let img_data = purple_imgstore_get_data(img);
-        let img_size = purple_imgstore_get_size(img);
-        let img_filename = purple_imgstore_get_filename(img);
+        // show the message in order, the video itself may take long
+        let html = format!("[Video {}s] downloading...", length);
+        let _ = sender.send(SrvMsg::YieldEvent);
+        if has_thumb {
+            let _ = sender.send(SrvMsg::AppendThumbnailMessage(thumb_path, html, msg.clone()));
+        } else {
+            let _ = sender.send(SrvMsg::AppendMediaMessage(html, msg.clone()));
+        }
 
-        let id = purple_imgstore_add_with_id(img_data as *mut c_void, img_size, img_filename);
+        let saved = File::create(&video_path)
+            .map_err(|e| format!("{}", e))
+            .and_then(|mut f| client.get_video(&msg_id, &mut f).map_err(|e| format!("{}", e)));
 
-        let sender = SRV_MSG.0.lock().unwrap();
-        sender.send(SrvMsg::YieldEvent).unwrap();
-        sender.send(SrvMsg::AppendImageMessage(id, msg)).unwrap()
+        let html = match saved {
+            Ok(_) => {
+                format!(r#"[Video {}s] <a href="file://{}">{}</a>"#,
+                        length,
+                        video_path.display(),
+                        video_path.file_name().unwrap().to_string_lossy())
+            }
+            Err(e) => {
+                println!("fetch video failed: {}", e);
+                let _ = fs::remove_file(&video_path);
+                format!("[Video {}s] download failed", length)
+            }
+        };
+
+        let _ = sender.send(SrvMsg::YieldEvent);
+        let _ = sender.send(SrvMsg::AppendMediaMessage(html, msg));
     });
 }
 
-unsafe fn append_text_message(msg: &Value) {
+unsafe fn append_app_message(conn: &Connection, msg: &Value) {
+
+    let app_msg = AppMsg::from_json(msg);
+    append_media_message(conn, &app_msg.to_html(), msg);
+
+    // files we sent from the phone are not offered back to us
+    let from_self = msg["FromUserName"].as_str() == Some(&conn.client().user_name());
+    if let (AppMsg::File { .. }, false) = (app_msg, from_self) {
+        xfer::receive_file(conn, msg);
+    }
+}
+
+fn append_location_message(conn: &Connection, msg: &Value) {
+    match Location::from_json(msg) {
+        Some(location) => append_media_message(conn, &location.to_html(), msg),
+        None => unsafe { append_text_message(conn, msg) },
+    }
+}
+
+unsafe fn append_text_message(conn: &Connection, msg: &Value) {
 
     let content = msg["Content"].as_str().unwrap();
     let content_cstring = CString::new(content).unwrap();
@@ -971,9 +817,11 @@ unsafe fn append_text_message(msg: &Value) {
     let from = CString::new(src).unwrap();
     let dest = msg["ToUserName"].as_str().unwrap();
     let time = msg["CreateTime"].as_i64().unwrap();
+    let send_flag = delivery_flags(conn, PURPLE_MESSAGE_SEND, time);
+    let recv_flag = delivery_flags(conn, PURPLE_MESSAGE_RECV, time);
 
     if src.starts_with("@@") {
-        let conv = conversion(PURPLE_CONV_TYPE_CHAT, src);
+        let conv = conversion(conn, PURPLE_CONV_TYPE_CHAT, src);
         let chat = purple_conversation_get_chat_data(conv);
 
         // split content to find real sender
@@ -986,53 +834,68 @@ unsafe fn append_text_message(msg: &Value) {
                 purple_conv_chat_write(chat,
                                        sender.as_ptr(),
                                        content.as_ptr(),
-                                       PURPLE_MESSAGE_RECV,
+                                       recv_flag,
                                        time);
             }
             None => {
                 purple_conv_chat_write(chat,
                                        from.as_ptr(),
                                        content_cstring.as_ptr(),
-                                       PURPLE_MESSAGE_RECV | PURPLE_MESSAGE_SYSTEM,
+                                       recv_flag | PURPLE_MESSAGE_SYSTEM,
                                        time);
             }
         }
     } else if dest.starts_with("@@") {
-        let conv = conversion(PURPLE_CONV_TYPE_CHAT, dest);
+        let conv = conversion(conn, PURPLE_CONV_TYPE_CHAT, dest);
         let chat = purple_conversation_get_chat_data(conv);
         purple_conv_chat_write(chat,
                                from.as_ptr(),
                                content_cstring.as_ptr(),
-                               PURPLE_MESSAGE_SEND,
+                               send_flag,
                                time);
     } else {
-        let self_name = {
-            let wechat = WECHAT.read().unwrap();
-            wechat.user_name().to_owned()
-        };
+        let self_name = conn.client().user_name();
 
         if self_name != src {
-            let account_ptr = ACCOUNT.read().unwrap().as_ptr() as *mut PurpleAccount;
+            let account_ptr = conn.account();
             let gc = (*account_ptr).gc;
 
             serv_got_im(gc,
                         from.as_ptr(),
                         content_cstring.as_ptr(),
-                        PURPLE_MESSAGE_RECV,
+                        recv_flag,
                         time);
         } else {
-            let conv = conversion(PURPLE_CONV_TYPE_IM, dest);
+            let conv = conversion(conn, PURPLE_CONV_TYPE_IM, dest);
             let im = purple_conversation_get_im_data(conv);
             purple_conv_im_write(im,
                                  from.as_ptr(),
                                  content_cstring.as_ptr(),
-                                 PURPLE_MESSAGE_SEND,
+                                 send_flag,
                                  time);
         }
     }
 }
 
-fn append_purple_chat_message(from: &str, dest: &str, sender: &str, content: &str, time: i64) {
+/// messages created before the connection was made were received while
+/// offline, mark them as delayed.
+fn delivery_flags(conn: &Connection,
+                  flags: PurpleMessageFlags,
+                  time: i64)
+                  -> PurpleMessageFlags {
+    if time < conn.login_time {
+        flags | PURPLE_MESSAGE_DELAYED
+    } else {
+        flags
+    }
+}
+
+fn append_purple_chat_message(conn: &Connection,
+                              from: &str,
+                              dest: &str,
+                              sender: &str,
+                              content: &str,
+                              time: i64) {
 
     let content_cstring = CString::new(content).unwrap();
     let from_cstring = CString::new(from).unwrap();
@@ -1051,11 +914,13 @@ fn append_purple_chat_message(from: &str, dest: &str, sender: &str, content: &st
             PURPLE_MESSAGE_RECV
         }
     };
+    let send_flag = delivery_flags(conn, send_flag, time);
+    let recv_flag = delivery_flags(conn, recv_flag, time);
 
     if from.starts_with("@@") {
         let sender_cstring = CString::new(sender).unwrap();
         unsafe {
-            let conv = conversion(PURPLE_CONV_TYPE_CHAT, from);
+            let conv = conversion(conn, PURPLE_CONV_TYPE_CHAT, from);
             let chat = purple_conversation_get_chat_data(conv);
 
             purple_conv_chat_write(chat,
@@ -1066,7 +931,7 @@ fn append_purple_chat_message(from: &str, dest: &str, sender: &str, content: &st
         }
     } else if dest.starts_with("@@") {
         unsafe {
-            let conv = conversion(PURPLE_CONV_TYPE_CHAT, dest);
+            let conv = conversion(conn, PURPLE_CONV_TYPE_CHAT, dest);
             let chat = purple_conversation_get_chat_data(conv);
             purple_conv_chat_write(chat,
                                    from_cstring.as_ptr(),
@@ -1077,7 +942,28 @@ fn append_purple_chat_message(from: &str, dest: &str, sender: &str, content: &st
     }
 }
 
-fn append_purple_im_message(from: &str, dest: &str, content: &str, time: i64) {
+/// a group message without a sender, written as a system line.
+fn append_purple_chat_notice(conn: &Connection, from: &str, content: &str, time: i64) {
+
+    let content_cstring = CString::new(content).unwrap();
+    let from_cstring = CString::new(from).unwrap();
+    let mut flags = PURPLE_MESSAGE_RECV | PURPLE_MESSAGE_SYSTEM;
+    if content.contains("<IMG ID=") {
+        flags = flags | PURPLE_MESSAGE_IMAGES;
+    }
+
+    unsafe {
+        let conv = conversion(conn, PURPLE_CONV_TYPE_CHAT, from);
+        let chat = purple_conversation_get_chat_data(conv);
+        purple_conv_chat_write(chat,
+                               from_cstring.as_ptr(),
+                               content_cstring.as_ptr(),
+                               delivery_flags(conn, flags, time),
+                               time);
+    }
+}
+
+fn append_purple_im_message(conn: &Connection, from: &str, dest: &str, content: &str, time: i64) {
 
     let content_cstring = CString::new(content).unwrap();
     let from_cstring = CString::new(from).unwrap();
@@ -1096,14 +982,13 @@ fn append_purple_im_message(from: &str, dest: &str, content: &str, time: i64) {
             PURPLE_MESSAGE_RECV
         }
     };
+    let send_flag = delivery_flags(conn, send_flag, time);
+    let recv_flag = delivery_flags(conn, recv_flag, time);
 
-    let self_name = {
-        let wechat = WECHAT.read().unwrap();
-        wechat.user_name().to_owned()
-    };
+    let self_name = conn.client().user_name();
 
     if self_name != from {
-        let account_ptr = ACCOUNT.read().unwrap().as_ptr() as *mut PurpleAccount;
+        let account_ptr = conn.account();
 
         unsafe {
             let gc = (*account_ptr).gc;
@@ -1115,7 +1000,7 @@ fn append_purple_im_message(from: &str, dest: &str, content: &str, time: i64) {
         }
     } else {
         unsafe {
-            let conv = conversion(PURPLE_CONV_TYPE_IM, dest);
+            let conv = conversion(conn, PURPLE_CONV_TYPE_IM, dest);
             let im = purple_conversation_get_im_data(conv);
             purple_conv_im_write(im,
                                  from_cstring.as_ptr(),
@@ -1126,37 +1011,228 @@ fn append_purple_im_message(from: &str, dest: &str, content: &str, time: i64) {
     }
 }
 
-fn append_message(json: &Value) {
+fn append_message(conn: &Connection, message: &Message) {
 
-    if let Value::Array(ref list) = json["AddMsgList"] {
-        for msg in list {
-            println!("got message =========================\n {}", json);
+    let msg = &message.raw;
+    if !message.id.is_empty() {
+        conn.remember_message(&message.id, excerpt(msg));
+    }
 
-            let msg_type = msg["MsgType"].as_i64().unwrap();
-            match msg_type {
-                // 51 is wechat init message
-                51 => continue,
-                3 => unsafe { process_image_message(msg) },
-                47 => unsafe { process_emoji_image(msg) },
-                _ => unsafe { append_text_message(msg) },
-            }
+    match message.msg_type {
+        3 => unsafe { process_image_message(conn, msg) },
+        34 => unsafe { process_voice_message(conn, msg) },
+        37 => unsafe { request_authorization(conn, msg) },
+        42 => append_media_message(conn, &Card::from_json(msg).to_html(), msg),
+        48 => append_location_message(conn, msg),
+        43 | 62 => unsafe { process_video_message(conn, msg) },
+        47 => unsafe { process_emoji_image(conn, msg) },
+        49 => unsafe { append_app_message(conn, msg) },
+        10000 => unsafe { append_notice_message(conn, msg) },
+        10002 => unsafe { append_revoke_message(conn, msg) },
+        _ => unsafe { append_text_message(conn, msg) },
+    }
+}
+
+/// short plain text of a message, to refer to it later.
+fn excerpt(msg: &Value) -> String {
+
+    let text = match msg["MsgType"].as_i64().unwrap_or(0) {
+        1 => {
+            let content = msg["Content"].as_str().unwrap_or("");
+            let regex = Regex::new(r#"^@\w+:(?:<br/>)*"#).unwrap();
+            regex.replace(content, "").replace("<br/>", " ")
+        }
+        3 => "[Image]".to_owned(),
+        34 => "[Voice]".to_owned(),
+        43 | 62 => "[Video]".to_owned(),
+        42 => "[Contact Card]".to_owned(),
+        47 => "[Sticker]".to_owned(),
+        48 => "[Location]".to_owned(),
+        49 => {
+            let title = msg["FileName"].as_str().unwrap_or("");
+            format!("[App Message] {}", title)
+        }
+        _ => "[Message]".to_owned(),
+    };
+
+    if text.chars().count() > EXCERPT_LEN {
+        format!("{}...", text.chars().take(EXCERPT_LEN).collect::<String>())
+    } else {
+        text
+    }
+}
+
+/// an authorization prompt waiting for an answer.
+struct AuthRequest {
+    account: *mut PurpleAccount,
+    request: FriendRequest,
+}
+
+/// MsgType 37, somebody wants to add us as a friend.
+unsafe fn request_authorization(conn: &Connection, msg: &Value) {
+
+    let request = match FriendRequest::from_json(msg) {
+        Some(request) => request,
+        None => return append_text_message(conn, msg),
+    };
+
+    println!("friend request: {} ({}): {}",
+             request.nick_name,
+             request.user_name,
+             request.content);
+
+    let account = conn.account();
+    let remote_user = CString::new(request.user_name.as_str()).unwrap();
+    let alias = CString::new(request.nick_name.as_str()).unwrap();
+    let message = CString::new(request.content.as_str()).unwrap();
+    let on_list = if purple_find_buddy(account, remote_user.as_ptr()) != null_mut() {
+        TRUE
+    } else {
+        FALSE
+    };
+
+    let data = Box::new(AuthRequest {
+                            account: account,
+                            request: request,
+                        });
+
+    purple_account_request_authorization(account,
+                                         remote_user.as_ptr(),
+                                         null_mut(),
+                                         alias.as_ptr(),
+                                         message.as_ptr(),
+                                         on_list,
+                                         Some(authorize_cb),
+                                         Some(deny_cb),
+                                         Box::into_raw(data) as *mut c_void);
+}
+
+unsafe extern "C" fn authorize_cb(data: *mut c_void) {
+
+    let data = Box::from_raw(data as *mut AuthRequest);
+    let conn = match Connection::from_account(data.account) {
+        Some(conn) => conn,
+        None => return,
+    };
+
+    let client = conn.client();
+    let sender = conn.server_message_sender();
+    let request = data.request;
+
+    thread::spawn(move || if let Err(e) = client.accept_friend(&request) {
+                      println!("accept friend failed: {}", e);
+                      let message = format!("Could not accept the friend request from {}: {}",
+                                            request.nick_name,
+                                            e);
+                      let _ = sender.send(SrvMsg::ShowMessageBox(message));
+                  });
+}
+
+/// the web protocol has no way to decline, the request is just ignored.
+unsafe extern "C" fn deny_cb(data: *mut c_void) {
+    drop(Box::from_raw(data as *mut AuthRequest));
+}
+
+/// MsgType 10000, notices from the server such as group invitations, red
+/// packets or warnings about a contact who is no longer a friend.
+unsafe fn append_notice_message(conn: &Connection, msg: &Value) {
+
+    let content = msg["Content"].as_str().unwrap_or("");
+    let regex = Regex::new(r#"</?_wc_custom_link_[^>]*>"#).unwrap();
+    let text = regex.replace_all(content, "").replace('\n', "<br/>");
+
+    println!("notice: {}", text);
+
+    append_system_message(conn, msg, &text);
+
+    let src = msg["FromUserName"].as_str().unwrap_or("");
+    if src.starts_with("@@") && is_membership_notice(&text) {
+        let client = conn.client();
+        let chat = src.to_owned();
+        thread::spawn(move || if let Err(e) = client.refresh_group(&chat) {
+                          println!("refresh group {} failed: {}", chat, e);
+                      });
+    }
+}
+
+/// whether a group notice says somebody joined or left.
+fn is_membership_notice(text: &str) -> bool {
+    let regex = Regex::new("加入了?群聊|移出了?群聊|退出了?群聊|\
+                            joined the group chat|left the group chat|\
+                            to the group chat|from the group chat")
+        .unwrap();
+
+    regex.is_match(text)
+}
+
+/// MsgType 10002, a message was recalled by its sender.
+unsafe fn append_revoke_message(conn: &Connection, msg: &Value) {
+
+    let content = appmsg::unescape(msg["Content"].as_str().unwrap_or(""));
+    let msg_id = appmsg::tag(&content, "msgid").unwrap_or_default();
+    let replace = appmsg::tag(&content, "replacemsg")
+        .unwrap_or_else(|| "A message was recalled".to_owned());
+
+    println!("revoke message {}: {}", msg_id, replace);
+
+    let text = match conn.find_message(&msg_id) {
+        Some(excerpt) => {
+            // the replacement reads `"name" recalled a message`
+            let regex = Regex::new(r#"^"(.+)""#).unwrap();
+            let name = regex.captures(&replace)
+                .map(|caps| caps.get(1).unwrap().as_str().to_owned())
+                .unwrap_or_else(|| msg["FromUserName"].as_str().unwrap_or("").to_owned());
+
+            format!("{} recalled a message: {}", name, excerpt)
         }
+        None => replace,
+    };
+
+    append_system_message(conn, msg, &text);
+}
+
+/// write `text` as a system message to the conversation of `msg`.
+unsafe fn append_system_message(conn: &Connection, msg: &Value, text: &str) {
+
+    let src = msg["FromUserName"].as_str().unwrap();
+    let dest = msg["ToUserName"].as_str().unwrap();
+    let time = msg["CreateTime"].as_i64().unwrap();
+    let flags = delivery_flags(conn, PURPLE_MESSAGE_SYSTEM, time);
+    let text = CString::new(text).unwrap();
+    let empty = CString::new("").unwrap();
+
+    if src.starts_with("@@") || dest.starts_with("@@") {
+        let chat_id = if src.starts_with("@@") { src } else { dest };
+        let conv = conversion(conn, PURPLE_CONV_TYPE_CHAT, chat_id);
+        let chat = purple_conversation_get_chat_data(conv);
+        purple_conv_chat_write(chat, empty.as_ptr(), text.as_ptr(), flags, time);
+    } else {
+        let who = if conn.client().user_name() == src { dest } else { src };
+        let conv = conversion(conn, PURPLE_CONV_TYPE_IM, who);
+        let im = purple_conversation_get_im_data(conv);
+        purple_conv_im_write(im, empty.as_ptr(), text.as_ptr(), flags, time);
     }
 }
 
-unsafe fn add_buddy(user: &User) {
+unsafe fn add_buddy(conn: &Connection, user: &User) {
 
     println!("add_buddy: {} ({})", user.nick_name(), user.alias());
 
-    let account = ACCOUNT.read().unwrap().as_ptr() as *mut PurpleAccount;
+    let account = conn.account();
     let group_name = CString::new("Wechat").unwrap();
     let group = purple_find_group(group_name.as_ptr());
 
     let user_name = user.user_name_str();
 
-    let buddy = purple_buddy_new(account, user_name.as_ptr(), user.nick_name_str().as_ptr());
-    (*buddy).node.flags = PURPLE_BLIST_NODE_FLAG_NO_SAVE;
-    purple_blist_add_buddy(buddy, null_mut(), group, null_mut());
+    // a buddy added from the add buddy dialog waits for the request to be accepted
+    let buddy = purple_find_buddy(account, user_name.as_ptr());
+    if buddy != null_mut() {
+        purple_blist_alias_buddy(buddy, user.nick_name_str().as_ptr());
+    } else {
+        let buddy = purple_buddy_new(account, user_name.as_ptr(), user.nick_name_str().as_ptr());
+        (*buddy).node.flags = PURPLE_BLIST_NODE_FLAG_NO_SAVE;
+        purple_blist_add_buddy(buddy, null_mut(), group, null_mut());
+    }
 
     // set status to available
     let available = CString::new("available").unwrap();
@@ -1164,60 +1240,268 @@ unsafe fn add_buddy(user: &User) {
                                 user_name.as_ptr(),
                                 available.as_ptr(),
                                 null_mut() as *mut c_void);
+
+    conn.request_icon(user.user_name(), user.head_img_url());
 }
 
-pub fn login() {
+unsafe fn update_buddy(conn: &Connection, user: &User) {
 
-    unsafe {
-        purple_timeout_add(1000, Some(check_srv), null_mut());
+    println!("update_buddy: {} ({})", user.nick_name(), user.alias());
+
+    let user_name = user.user_name_str();
+    let buddy = purple_find_buddy(conn.account(), user_name.as_ptr());
+    if buddy == null_mut() {
+        return add_buddy(conn, user);
+    }
+
+    purple_blist_alias_buddy(buddy, user.nick_name_str().as_ptr());
+    conn.request_icon(user.user_name(), user.head_img_url());
+}
+
+unsafe fn remove_buddy(conn: &Connection, user_name: &str) {
+
+    println!("remove_buddy: {}", user_name);
+
+    let user_name = CString::new(user_name).unwrap();
+    let buddy = purple_find_buddy(conn.account(), user_name.as_ptr());
+    if buddy != null_mut() {
+        purple_blist_remove_buddy(buddy);
+    }
+}
+
+pub unsafe fn login(account: *mut PurpleAccount) {
+
+    let gc = purple_account_get_connection(account);
+    let (tx, rx) = channel();
+    let client = Arc::new(Client::new(Box::new(HttpsTransport::new()), Endpoints::from_env(), tx));
+
+    if let Some(session) = load_session(account) {
+        client.restore_session(&session);
+    }
+
+    let srv_msg = channel();
+    let icons = icon::fetcher(client.clone(), srv_msg.0.clone());
+
+    let conn = Box::new(Connection {
+                            gc: Pointer::new().set(gc as *mut c_void),
+                            client: client.clone(),
+                            events: rx,
+                            srv_msg: srv_msg,
+                            verify_handle: Cell::new(Pointer::new()),
+                            check_srv_handle: Cell::new(0),
+                            login_time: time_stamp() / 1000,
+                            messages: RefCell::new(VecDeque::new()),
+                            icons: icons,
+                            blist_chats: RefCell::new(HashMap::new()),
+                        });
+    let conn = Box::into_raw(conn);
+    purple_connection_set_protocol_data(gc, conn as *mut c_void);
+    (*conn).check_srv_handle.set(purple_timeout_add(1000, Some(check_srv), gc as *mut c_void));
+
+    thread::spawn(move || { client.run(); });
+}
+
+/// tear down everything `login` set up.
+pub unsafe fn close(gc: *mut PurpleConnection) {
+
+    let account = purple_connection_get_account(gc);
+
+    let conn = purple_connection_get_protocol_data(gc) as *mut Connection;
+    if !conn.is_null() {
+        purple_connection_set_protocol_data(gc, null_mut());
+        let conn = Box::from_raw(conn);
+
+        // stop dispatching events to this connection
+        purple_timeout_remove(conn.check_srv_handle.get());
+
+        // close the login dialog, if any
+        close_verify_image(&conn);
+
+        let client = conn.client();
+        client.cancel();
+
+        // remove chats of this session from buddy list
+        for (_, chat_ptr) in conn.blist_chats.borrow_mut().drain() {
+            purple_blist_remove_chat(chat_ptr.as_ptr() as *mut PurpleChat);
+        }
+        client.clear();
+
+        // only end the server side session if the account was disabled, a
+        // reconnect or restart resumes it instead of asking for a qr-code.
+        let enabled = purple_account_get_enabled(account, purple_core_get_ui());
+        thread::spawn(move || if enabled == FALSE {
+                          if let Err(e) = client.logout() {
+                              println!("logout failed: {}", e);
+                          }
+                      });
+    }
+
+    // remove buddies of this session
+    let buddies = purple_find_buddies(account, null_mut());
+    let mut node = buddies;
+    while node != null_mut() {
+        purple_blist_remove_buddy((*node).data as *mut PurpleBuddy);
+        node = (*node).next;
+    }
+    g_slist_free(buddies);
+}
+
+unsafe fn load_session(account: *mut PurpleAccount) -> Option<Value> {
+
+    let key = CString::new(SESSION_SETTING).unwrap();
+    let empty = CString::new("").unwrap();
+    let session = purple_account_get_string(account, key.as_ptr(), empty.as_ptr());
+    if session == null_mut() {
+        return None;
+    }
+
+    CStr::from_ptr(session).to_string_lossy().parse::<Value>().ok()
+}
+
+unsafe fn save_session(conn: &Connection, session: &Value) {
+
+    let account = conn.account();
+    let key = CString::new(SESSION_SETTING).unwrap();
+    let session = CString::new(session.to_string()).unwrap();
+    purple_account_set_string(account, key.as_ptr(), session.as_ptr());
+}
+
+unsafe fn logged_in(conn: &Connection, user_name: &str) {
+
+    let client = conn.client();
+    let nick_name = client.nick_name();
+
+    // refersh current user name
+    let uname = CString::new(user_name).unwrap();
+    let alias = CString::new(if nick_name.is_empty() { user_name } else { &nick_name }).unwrap();
+    println!("set usernmae: {:?} ({:?})", uname, alias);
+    purple_account_set_username(conn.account(),
+                                uname.as_ptr());
+    purple_account_set_alias(conn.account(),
+                             alias.as_ptr());
+
+    conn.request_icon(user_name, &client.head_img_url());
+}
+
+unsafe fn disconnected(conn: &Connection, reason: &Disconnect) {
+
+    // network errors let libpurple reconnect, which resumes the saved session
+    let (error, message) = match *reason {
+        Disconnect::Network(ref m) => (PURPLE_CONNECTION_ERROR_NETWORK_ERROR, m),
+        Disconnect::SessionExpired(ref m) => (PURPLE_CONNECTION_ERROR_AUTHENTICATION_FAILED, m),
+        Disconnect::Cancelled => return,
+    };
+
+    let message = CString::new(message.as_str()).unwrap();
+    purple_connection_error_reason(conn.gc(), error, message.as_ptr());
+}
+
+/// give up on the connection. libpurple retries network errors, which
+/// resumes the saved session, a rejected login needs a new qr-code anyway.
+unsafe fn login_failed(conn: &Connection, reason: &LoginError) {
+
+    close_verify_image(conn);
+
+    let (error, message) = match *reason {
+        LoginError::Network(ref m) => (PURPLE_CONNECTION_ERROR_NETWORK_ERROR, m),
+        LoginError::Rejected(ref m) => (PURPLE_CONNECTION_ERROR_AUTHENTICATION_FAILED, m),
+    };
+
+    let message = CString::new(format!("Login failed: {}", message)).unwrap();
+    purple_connection_error_reason(conn.gc(), error, message.as_ptr());
+}
+
+unsafe fn close_verify_image(conn: &Connection) {
+
+    // scan successful, close dialog
+    let vh = conn.verify_handle.replace(Pointer::new());
+    if vh.as_ptr() != null_mut() {
+        purple_request_close(PURPLE_REQUEST_FIELDS, vh.as_ptr());
     }
+}
 
-    std::thread::spawn(|| { start_login(); });
+pub unsafe fn show_verify_image(conn: &Connection, image: &[u8]) {
+    show_login_dialog(conn, "Scan qr-code to login.", None, Some(image));
 }
 
-pub unsafe fn show_verify_image<T: AsRef<str>>(path: T) {
+unsafe fn show_confirm_dialog(conn: &Connection, avatar: Option<&[u8]>) {
+    show_login_dialog(conn, "Confirm on your phone.",
+                      Some("Scanned, please confirm the login on your phone."),
+                      avatar);
+}
 
-    // login qr-code
-    let mut qr_image = File::open(path.as_ref()).unwrap();
-    let mut buf = Vec::new();
-    let qr_image_size = qr_image.read_to_end(&mut buf).unwrap();
-    let qr_image_buf = CString::from_vec_unchecked(buf);
+unsafe fn show_login_dialog(conn: &Connection,
+                            title: &str,
+                            message: Option<&str>,
+                            image: Option<&[u8]>) {
 
-    let qr_code_id = CString::new("qrcode").unwrap();
-    let qr_code_field = purple_request_field_image_new(qr_code_id.as_ptr(),
-                                                       qr_code_id.as_ptr(),
-                                                       qr_image_buf.as_ptr(),
-                                                       qr_image_size as u64);
+    // a refreshed qr-code or the confirm dialog replaces the current one
+    close_verify_image(conn);
 
     let group = purple_request_field_group_new(null_mut());
-    purple_request_field_group_add_field(group, qr_code_field);
+
+    let image_id = CString::new("image").unwrap();
+    if let Some(image) = image {
+        let image_size = image.len();
+        let image_buf = CString::from_vec_unchecked(image.to_vec());
+
+        let image_field = purple_request_field_image_new(image_id.as_ptr(),
+                                                         image_id.as_ptr(),
+                                                         image_buf.as_ptr(),
+                                                         image_size as u64);
+        purple_request_field_group_add_field(group, image_field);
+    }
 
     let fields = purple_request_fields_new();
     purple_request_fields_add_group(fields, group);
 
-    let title = CString::new("Scan qr-code to login.").unwrap();
+    let title = CString::new(title).unwrap();
+    let message = message.map(|m| CString::new(m).unwrap());
+    let secondary = match message {
+        Some(ref m) => m.as_ptr(),
+        None => null_mut(),
+    };
     let ok = CString::new("Ok").unwrap();
     let cancel = CString::new("Cancel").unwrap();
-    let account = ACCOUNT.read().unwrap().as_ptr() as *mut PurpleAccount;
-    let verify_handle = purple_request_fields(purple_account_get_connection(account) as
-                                              *mut c_void, // handle
+    let cancel_cb = std::mem::transmute::<unsafe extern "C" fn(*mut c_void,
+                                                               *mut PurpleRequestFields),
+                                          unsafe extern "C" fn()>(cancel_login_cb);
+    let account = conn.account();
+    let verify_handle = purple_request_fields(conn.gc() as *mut c_void, // handle
                                               title.as_ptr(), // title
                                               title.as_ptr(), // primary
-                                              null_mut(), // secondary
+                                              secondary, // secondary
                                               fields, // fields
                                               ok.as_ptr(), // ok_text
                                               Some(ok_cb), // ok_cb
                                               cancel.as_ptr(), // cancel_text
-                                              None, // cancel_cb
+                                              Some(cancel_cb), // cancel_cb
                                               account, // account
                                               null_mut(), // who
                                               null_mut(), // conv
-                                              null_mut()); // user_data
+                                              conn.gc() as *mut c_void); // user_data
 
     assert!(verify_handle != null_mut());
-    let mut vh = VERIFY_HANDLE.lock().unwrap();
-    vh.set(verify_handle);
+    conn.verify_handle.set(Pointer::new().set(verify_handle));
 }
 
 extern "C" fn ok_cb() {}
 
+/// the login dialog was cancelled, stop waiting for the qr-code. the dialog
+/// is closed with the connection, so `gc` is still valid.
+unsafe extern "C" fn cancel_login_cb(gc: *mut c_void, _: *mut PurpleRequestFields) {
+
+    let gc = gc as *mut PurpleConnection;
+    let conn = match Connection::from_gc(gc) {
+        Some(conn) => conn,
+        None => return,
+    };
+
+    // already closed by libpurple
+    conn.verify_handle.set(Pointer::new());
+    conn.client().cancel();
+
+    let message = CString::new("Login cancelled.").unwrap();
+    purple_connection_error_reason(gc, PURPLE_CONNECTION_ERROR_OTHER_ERROR, message.as_ptr());
+}
+
@@ -0,0 +1,198 @@
+
+extern crate std;
+extern crate hyper;
+extern crate hyper_native_tls;
+
+use self::hyper::Client;
+use self::hyper::header::{Headers, SetCookie};
+use self::hyper::net::HttpsConnector;
+use self::hyper_native_tls::NativeTlsClient;
+use std::io::Read;
+use std::env;
+use std::fmt;
+
+/// environment variable used to point the plugin at a stand-in server,
+/// e.g. `WECHAT_BASE_URL=http://127.0.0.1:8080`.
+const BASE_URL_ENV: &'static str = "WECHAT_BASE_URL";
+
+#[derive(Debug)]
+pub enum Error {
+    Http(hyper::Error),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Http(ref e) => write!(f, "http error: {}", e),
+            Error::Io(ref e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(e: hyper::Error) -> Error {
+        Error::Http(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// request headers, each a name and its raw value.
+pub type RawHeaders = [(String, Vec<u8>)];
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub cookies: Vec<String>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// Everything the protocol code needs from the network. The default
+/// implementation talks to the real service over https, tests can provide
+/// their own to answer requests in-process.
+pub trait Transport: Send + Sync {
+    fn get(&self, url: &str, headers: &RawHeaders) -> Result<Response>;
+    fn post(&self, url: &str, headers: &RawHeaders, body: &[u8]) -> Result<Response>;
+}
+
+/// `headers` as given to a `Transport`.
+pub fn raw_headers(headers: &Headers) -> Vec<(String, Vec<u8>)> {
+    headers.iter()
+        .map(|h| (h.name().to_owned(), h.value_string().into_bytes()))
+        .collect()
+}
+
+fn hyper_headers(raw: &RawHeaders) -> Headers {
+    let mut headers = Headers::new();
+    for &(ref name, ref value) in raw {
+        headers.append_raw(name.clone(), value.clone());
+    }
+
+    headers
+}
+
+pub struct HttpsTransport {
+    client: Client,
+}
+
+impl HttpsTransport {
+    pub fn new() -> HttpsTransport {
+        let ssl = NativeTlsClient::new().unwrap();
+        let connector = HttpsConnector::new(ssl);
+
+        HttpsTransport { client: Client::with_connector(connector) }
+    }
+}
+
+fn read_response(mut response: hyper::client::Response) -> Result<Response> {
+    let mut body = Vec::new();
+    response.read_to_end(&mut body)?;
+
+    let cookies = match response.headers.get::<SetCookie>() {
+        Some(cookies) => cookies.iter().cloned().collect(),
+        None => vec![],
+    };
+
+    Ok(Response {
+           status: response.status.to_u16(),
+           cookies: cookies,
+           body: body,
+       })
+}
+
+impl Transport for HttpsTransport {
+    fn get(&self, url: &str, headers: &RawHeaders) -> Result<Response> {
+        let response = self.client.get(url).headers(hyper_headers(headers)).send()?;
+
+        read_response(response)
+    }
+
+    fn post(&self, url: &str, headers: &RawHeaders, body: &[u8]) -> Result<Response> {
+        let response = self.client
+            .post(url)
+            .headers(hyper_headers(headers))
+            .body(body)
+            .send()?;
+
+        read_response(response)
+    }
+}
+
+/// Base urls of the hosts the web protocol talks to.
+#[derive(Debug, Clone)]
+pub struct Endpoints {
+    pub login: String,
+    pub web: String,
+    pub push: String,
+    /// media uploads.
+    pub file: String,
+}
+
+impl Endpoints {
+    /// the real wechat servers.
+    pub fn new() -> Endpoints {
+        Endpoints {
+            login: "https://login.web.wechat.com".to_owned(),
+            web: "https://web.wechat.com".to_owned(),
+            push: "https://webpush.web.wechat.com".to_owned(),
+            file: "https://file.web.wechat.com".to_owned(),
+        }
+    }
+
+    /// route every host to a single base url, used with a local stand-in server.
+    pub fn with_base(base: &str) -> Endpoints {
+        let base = base.trim_right_matches('/').to_owned();
+
+        Endpoints {
+            login: base.clone(),
+            web: base.clone(),
+            push: base.clone(),
+            file: base,
+        }
+    }
+
+    /// honor `WECHAT_BASE_URL` if set, otherwise use the real servers.
+    pub fn from_env() -> Endpoints {
+        match env::var(BASE_URL_ENV) {
+            Ok(ref base) if !base.is_empty() => Endpoints::with_base(base),
+            _ => Endpoints::new(),
+        }
+    }
+
+    pub fn login_url(&self, path: &str) -> String {
+        format!("{}{}", self.login, path)
+    }
+
+    pub fn web_url(&self, path: &str) -> String {
+        format!("{}{}", self.web, path)
+    }
+
+    pub fn push_url(&self, path: &str) -> String {
+        format!("{}{}", self.push, path)
+    }
+
+    pub fn file_url(&self, path: &str) -> String {
+        format!("{}{}", self.file, path)
+    }
+
+    pub fn web_referer(&self) -> String {
+        format!("{}/?&lang=zh_CN", self.web)
+    }
+
+    pub fn push_referer(&self) -> String {
+        format!("{}/?&lang=zh_CN", self.push)
+    }
+}
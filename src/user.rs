@@ -18,14 +18,18 @@ pub struct User {
 impl User {
     pub fn from_json(json: &Value) -> User {
         User {
-            user_name: json["UserName"].as_str().unwrap().to_owned(),
-            nick_name: json["NickName"].as_str().unwrap().to_owned(),
-            alias: json["Alias"].as_str().unwrap().to_owned(),
-            head_img_url: json["HeadImgUrl"].as_str().unwrap().to_owned(),
-            signature: json["Signature"].as_str().unwrap().to_owned(),
+            user_name: json["UserName"].as_str().unwrap_or("").to_owned(),
+            nick_name: json["NickName"].as_str().unwrap_or("").to_owned(),
+            alias: json["Alias"].as_str().unwrap_or("").to_owned(),
+            head_img_url: json["HeadImgUrl"].as_str().unwrap_or("").to_owned(),
+            signature: json["Signature"].as_str().unwrap_or("").to_owned(),
         }
     }
 
+    pub fn user_name(&self) -> &String {
+        &self.user_name
+    }
+
     pub fn alias(&self) -> &String {
         &self.alias
     }
@@ -34,6 +38,10 @@ impl User {
         &self.nick_name
     }
 
+    pub fn head_img_url(&self) -> &String {
+        &self.head_img_url
+    }
+
     pub fn user_name_str(&self) -> CString {
         CString::new(self.user_name.clone()).unwrap()
     }
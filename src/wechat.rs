@@ -8,9 +8,14 @@ extern crate serde_json;
 
 mod pointer;
 mod server;
-mod user;
-mod chatroom;
+pub mod user;
+pub mod chatroom;
 mod message;
+mod xfer;
+mod icon;
+pub mod transport;
+pub mod client;
+pub mod appmsg;
 
 use std::os::raw::{c_void, c_char};
 use std::ptr::null_mut;
@@ -20,7 +25,7 @@ use std::sync::RwLock;
 use purple_sys::*;
 use message::*;
 use pointer::Pointer;
-use server::ACCOUNT;
+use server::Connection;
 use server::{send_im, send_chat, find_blist_chat, find_chat_token};
 
 const TRUE: i32 = 1;
@@ -29,7 +34,12 @@ const FALSE: i32 = 0;
 lazy_static!{
     static ref PLUGIN: RwLock<Pointer> = RwLock::new(Pointer::new());
     static ref ICON_FILE: CString = CString::new("icq").unwrap();
+    static ref ICON_FORMAT: CString = CString::new("jpg,png,gif").unwrap();
     static ref WECHAT_CATEGORY: CString = CString::new("Wechat").unwrap();
+    static ref RECALL_CMD: CString = CString::new("recall").unwrap();
+    static ref RECALL_ARGS: CString = CString::new("").unwrap();
+    static ref RECALL_HELP: CString =
+        CString::new("recall:  Recall the last message you sent, within 2 minutes.").unwrap();
 }
 
 fn append_item(list: *mut GList, item: *mut c_void) -> *mut GList {
@@ -88,8 +98,6 @@ unsafe extern "C" fn login(account: *mut PurpleAccount) {
 
     println!("account: {:?}", account);
 
-    ACCOUNT.write().unwrap().set(account as *mut c_void);
-
     purple_connection_set_state(purple_account_get_connection(account), PURPLE_CONNECTED);
 
     // clear old buddy list
@@ -114,7 +122,7 @@ unsafe extern "C" fn login(account: *mut PurpleAccount) {
     (*group).node.flags = PURPLE_BLIST_NODE_FLAG_NO_SAVE;
     purple_blist_add_group(group, null_mut());
 
-    std::thread::spawn(|| { server::login(); });
+    server::login(account);
 }
 
 extern "C" fn chat_info(_: *mut PurpleConnection) -> *mut GList {
@@ -131,16 +139,16 @@ unsafe extern "C" fn join_chat(gc: *mut PurpleConnection, components: *mut GHash
     let id = CStr::from_ptr(g_hash_table_lookup(components, chat_key.as_ptr() as *const c_void) as
                             *const i8);
     let id_string: String = id.to_string_lossy().into_owned();
-    let token = find_chat_token(&id_string);
+    let conn = match Connection::from_gc(gc) {
+        Some(conn) => conn,
+        None => return,
+    };
+    let token = find_chat_token(conn, &id_string);
 
     println!("id = {:?}, token = {}", id, token);
 
-    let account = ACCOUNT.read().unwrap().as_ptr() as *mut PurpleAccount;
-
-    serv_got_joined_chat(purple_account_get_connection(account),
-                         token as i32,
-                         id.as_ptr());
-    send_server_message(SrvMsg::RefreshChatMembers(id_string));
+    serv_got_joined_chat(gc, token as i32, id.as_ptr());
+    conn.send_server_message(SrvMsg::RefreshChatMembers(id_string));
 }
 
 extern "C" fn chat_info_defaults(_: *mut PurpleConnection, _: *const c_char) -> *mut GHashTable {
@@ -150,7 +158,9 @@ extern "C" fn chat_info_defaults(_: *mut PurpleConnection, _: *const c_char) ->
     table
 }
 
-extern "C" fn close(_: *mut PurpleConnection) {}
+unsafe extern "C" fn close(gc: *mut PurpleConnection) {
+    server::close(gc);
+}
 
 extern "C" fn buddy_list(gc: *mut PurpleConnection) -> *mut PurpleRoomlist {
 
@@ -237,6 +247,15 @@ pub extern "C" fn purple_init_plugin(plugin: *mut PurplePlugin) -> i32 {
 
     unsafe {
 
+        extra_info.options = OPT_PROTO_IM_IMAGE;
+        // avatars are jpeg, scale them down for display
+        extra_info.icon_spec.format = ICON_FORMAT.as_ptr() as *mut _;
+        extra_info.icon_spec.min_width = 0;
+        extra_info.icon_spec.min_height = 0;
+        extra_info.icon_spec.max_width = 96;
+        extra_info.icon_spec.max_height = 96;
+        extra_info.icon_spec.max_filesize = 0;
+        extra_info.icon_spec.scale_rules = PURPLE_ICON_SCALE_DISPLAY;
         extra_info.list_icon = Some(list_icon);
         extra_info.status_types = Some(status_types);
         extra_info.login = Some(login);
@@ -248,12 +267,27 @@ pub extern "C" fn purple_init_plugin(plugin: *mut PurplePlugin) -> i32 {
         extra_info.join_chat = Some(join_chat);
         extra_info.find_blist_chat = Some(find_blist_chat);
         extra_info.send_im = Some(send_im);
+        extra_info.add_buddy = Some(server::add_friend);
+        extra_info.add_buddy_with_invite = Some(server::add_friend_with_invite);
+        extra_info.can_receive_file = Some(xfer::can_receive_file);
+        extra_info.send_file = Some(xfer::send_file);
+        extra_info.new_xfer = Some(xfer::new_xfer);
 
         info.load = Some(callback);
         info.actions = Some(actions);
         info.extra_info = Box::into_raw(extra_info) as *mut c_void;
 
+        let id = info.id;
         (*plugin).info = Box::into_raw(info);
+
+        purple_cmd_register(RECALL_CMD.as_ptr(),
+                            RECALL_ARGS.as_ptr(),
+                            PURPLE_CMD_P_PRPL,
+                            PURPLE_CMD_FLAG_IM | PURPLE_CMD_FLAG_CHAT | PURPLE_CMD_FLAG_PRPL_ONLY,
+                            id,
+                            Some(server::recall_cmd),
+                            RECALL_HELP.as_ptr(),
+                            null_mut());
     };
 
     unsafe { purple_plugin_register(plugin) }
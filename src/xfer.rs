@@ -0,0 +1,229 @@
+
+extern crate std;
+
+use purple_sys::*;
+use serde_json::Value;
+use pointer::Pointer;
+use message::SrvMsg;
+use server::Connection;
+use std::os::raw::{c_void, c_char};
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::Read;
+use std::ptr::null_mut;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+const TRUE: i32 = 1;
+const FALSE: i32 = 0;
+
+/// state of a transfer, kept in `PurpleXfer.data`.
+struct XferData {
+    cancelled: Arc<AtomicBool>,
+    /// the `AddMsgList` entry of a received file.
+    msg: Option<Value>,
+}
+
+unsafe fn xfer_data<'a>(xfer: *mut PurpleXfer) -> Option<&'a XferData> {
+    let data = (*xfer).data as *const XferData;
+    if data.is_null() { None } else { Some(&*data) }
+}
+
+unsafe fn free_xfer_data(xfer: *mut PurpleXfer) {
+    let data = (*xfer).data as *mut XferData;
+    if !data.is_null() {
+        (*xfer).data = null_mut();
+        drop(Box::from_raw(data));
+    }
+}
+
+pub unsafe extern "C" fn can_receive_file(gc: *mut PurpleConnection, _: *const c_char) -> i32 {
+    if Connection::from_gc(gc).is_some() {
+        TRUE
+    } else {
+        FALSE
+    }
+}
+
+pub unsafe extern "C" fn new_xfer(gc: *mut PurpleConnection,
+                                  who: *const c_char)
+                                  -> *mut PurpleXfer {
+
+    let xfer = purple_xfer_new(purple_connection_get_account(gc), PURPLE_XFER_SEND, who);
+
+    purple_xfer_set_init_fnc(xfer, Some(send_init));
+    purple_xfer_set_cancel_send_fnc(xfer, Some(cancel));
+
+    xfer
+}
+
+pub unsafe extern "C" fn send_file(gc: *mut PurpleConnection,
+                                   who: *const c_char,
+                                   filename: *const c_char) {
+
+    let xfer = new_xfer(gc, who);
+
+    if filename != null_mut() {
+        purple_xfer_request_accepted(xfer, filename);
+    } else {
+        purple_xfer_request(xfer);
+    }
+}
+
+/// offer the attachment of a file message (MsgType 49, AppMsgType 6).
+pub unsafe fn receive_file(conn: &Connection, msg: &Value) {
+
+    let from = msg["FromUserName"].as_str().unwrap_or("");
+    let file_name = msg["FileName"].as_str().unwrap_or("file");
+    let size = msg["FileSize"]
+        .as_str()
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    println!("receive file: {} from {} ({} bytes)", file_name, from, size);
+
+    let from = CString::new(from).unwrap();
+    let file_name = CString::new(file_name).unwrap();
+    let xfer = purple_xfer_new(conn.account(), PURPLE_XFER_RECEIVE, from.as_ptr());
+    purple_xfer_set_filename(xfer, file_name.as_ptr());
+    purple_xfer_set_size(xfer, size as _);
+
+    let data = Box::new(XferData {
+                            cancelled: Arc::new(AtomicBool::new(false)),
+                            msg: Some(msg.clone()),
+                        });
+    (*xfer).data = Box::into_raw(data) as *mut c_void;
+
+    purple_xfer_set_init_fnc(xfer, Some(receive_init));
+    purple_xfer_set_request_denied_fnc(xfer, Some(free_xfer_data_cb));
+    purple_xfer_set_cancel_recv_fnc(xfer, Some(cancel));
+
+    purple_xfer_request(xfer);
+}
+
+unsafe extern "C" fn send_init(xfer: *mut PurpleXfer) {
+
+    let conn = match Connection::from_account(purple_xfer_get_account(xfer)) {
+        Some(conn) => conn,
+        None => return purple_xfer_cancel_local(xfer),
+    };
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let data = Box::new(XferData {
+                            cancelled: cancelled.clone(),
+                            msg: None,
+                        });
+    (*xfer).data = Box::into_raw(data) as *mut c_void;
+
+    let who = CStr::from_ptr(purple_xfer_get_remote_user(xfer)).to_string_lossy().into_owned();
+    let path = CStr::from_ptr(purple_xfer_get_local_filename(xfer)).to_string_lossy().into_owned();
+    let file_name = CStr::from_ptr(purple_xfer_get_filename(xfer)).to_string_lossy().into_owned();
+
+    let client = conn.client();
+    let sender = conn.server_message_sender();
+    let ptr = Pointer::new().set(xfer as *mut c_void);
+
+    // released when the worker reports back
+    purple_xfer_ref(xfer);
+    purple_xfer_start(xfer, -1, null_mut(), 0);
+
+    thread::spawn(move || {
+        let result = File::open(&path).and_then(|mut f| {
+            let mut data = Vec::new();
+            f.read_to_end(&mut data).map(|_| data)
+        });
+
+        let result = match result {
+            Ok(data) => {
+                client.send_file(&who, &file_name, &data, |sent| {
+                        let _ = sender.send(SrvMsg::XferProgress(ptr, sent));
+                        !cancelled.load(Ordering::SeqCst)
+                    })
+                    .map_err(|e| format!("{}", e))
+            }
+            Err(e) => Err(format!("{}", e)),
+        };
+
+        let _ = sender.send(SrvMsg::XferDone(ptr, result.err()));
+    });
+}
+
+unsafe extern "C" fn receive_init(xfer: *mut PurpleXfer) {
+
+    let conn = match Connection::from_account(purple_xfer_get_account(xfer)) {
+        Some(conn) => conn,
+        None => return purple_xfer_cancel_local(xfer),
+    };
+
+    let (cancelled, msg) = match xfer_data(xfer) {
+        Some(&XferData { ref cancelled, msg: Some(ref msg) }) => (cancelled.clone(), msg.clone()),
+        _ => return purple_xfer_cancel_local(xfer),
+    };
+
+    let path = CStr::from_ptr(purple_xfer_get_local_filename(xfer)).to_string_lossy().into_owned();
+
+    let client = conn.client();
+    let sender = conn.server_message_sender();
+    let ptr = Pointer::new().set(xfer as *mut c_void);
+
+    // released when the worker reports back
+    purple_xfer_ref(xfer);
+    purple_xfer_start(xfer, -1, null_mut(), 0);
+
+    thread::spawn(move || {
+        let result = File::create(&path).map_err(|e| format!("{}", e)).and_then(|mut f| {
+            client.get_media(&msg, &mut f, |received| {
+                    let _ = sender.send(SrvMsg::XferProgress(ptr, received));
+                    !cancelled.load(Ordering::SeqCst)
+                })
+                .map_err(|e| format!("{}", e))
+        });
+
+        let _ = sender.send(SrvMsg::XferDone(ptr, result.err()));
+    });
+}
+
+unsafe extern "C" fn cancel(xfer: *mut PurpleXfer) {
+    if let Some(data) = xfer_data(xfer) {
+        data.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+unsafe extern "C" fn free_xfer_data_cb(xfer: *mut PurpleXfer) {
+    free_xfer_data(xfer);
+}
+
+pub unsafe fn progress(xfer: Pointer, bytes: usize) {
+
+    let xfer = xfer.as_ptr() as *mut PurpleXfer;
+    if purple_xfer_is_canceled(xfer) != FALSE {
+        return;
+    }
+
+    purple_xfer_set_bytes_sent(xfer, bytes as _);
+    purple_xfer_update_progress(xfer);
+}
+
+pub unsafe fn done(xfer: Pointer, error: Option<String>) {
+
+    let xfer = xfer.as_ptr() as *mut PurpleXfer;
+
+    if purple_xfer_is_canceled(xfer) == FALSE {
+        match error {
+            None => {
+                purple_xfer_set_completed(xfer, TRUE);
+                purple_xfer_end(xfer);
+            }
+            Some(e) => {
+                println!("file transfer failed: {}", e);
+                let message = CString::new(format!("File transfer failed: {}", e)).unwrap();
+                purple_xfer_conversation_write(xfer, message.as_ptr() as *mut c_char, TRUE);
+                purple_xfer_cancel_remote(xfer);
+            }
+        }
+    }
+
+    free_xfer_data(xfer);
+    purple_xfer_unref(xfer);
+}
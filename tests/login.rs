@@ -0,0 +1,273 @@
+extern crate wechat;
+#[macro_use]
+extern crate serde_json;
+
+mod support;
+
+use std::sync::mpsc::{channel, Receiver};
+use serde_json::Value;
+use wechat::client::{Client, Event, LoginError, SyncCheckResult};
+use wechat::transport::{Endpoints, Response};
+use support::ScriptedTransport;
+
+const BASE: &'static str = "http://stand-in";
+
+fn client(transport: &ScriptedTransport) -> (Client, Receiver<Event>) {
+    let (tx, rx) = channel();
+    let client = Client::new(Box::new(transport.clone()), Endpoints::with_base(BASE), tx);
+
+    (client, rx)
+}
+
+fn redirect_uri() -> String {
+    format!("{}/cgi-bin/mmwebwx-bin/webwxnewloginpage?ticket=t&uuid=u&scan=1", BASE)
+}
+
+/// answers for `webwxnewloginpage`, `webwxinit` and `webwxbatchgetcontact`.
+fn script_login(transport: &ScriptedTransport) {
+    transport.respond_with("webwxnewloginpage?ticket",
+                           Response {
+                               status: 200,
+                               cookies: vec!["webwx_data_ticket=dt; Domain=stand-in".to_owned()],
+                               body: b"<error><ret>0</ret><skey>@crypt_1</skey>\
+                                       <wxsid>sid1</wxsid><wxuin>1001</wxuin>\
+                                       <pass_ticket>pt1</pass_ticket></error>"
+                                   .to_vec(),
+                           });
+
+    let init = json!({
+        "BaseResponse": { "Ret": 0 },
+        "SyncKey": { "Count": 2, "List": [{ "Key": 1, "Val": 100 }, { "Key": 2, "Val": 200 }] },
+        "User": { "UserName": "@me", "NickName": "me", "HeadImgUrl": "" },
+        "ContactList": [{ "UserName": "@@group" }, { "UserName": "@friend" }],
+    });
+    transport.respond("webwxinit", &init.to_string());
+
+    let groups = json!({
+        "BaseResponse": { "Ret": 0 },
+        "ContactList": [{
+            "UserName": "@@group",
+            "NickName": "group",
+            "MemberList": [{ "UserName": "@friend", "NickName": "friend", "DisplayName": "" }],
+        }],
+    });
+    transport.respond("webwxbatchgetcontact", &groups.to_string());
+}
+
+#[test]
+fn qr_login_waits_for_scan_and_confirmation() {
+    let transport = ScriptedTransport::new();
+    transport.respond("/jslogin", r#"window.QRLogin.code = 200; window.QRLogin.uuid = "u==";"#);
+    transport.respond("/qrcode/u==", "png");
+    transport.respond("mmwebwx-bin/login?", "window.code=408;");
+    transport.respond("mmwebwx-bin/login?",
+                      "window.code=201;window.userAvatar = 'data:img/jpg;base64,YXZhdGFy';");
+    transport.respond("mmwebwx-bin/login?",
+                      &format!(r#"window.code=200;window.redirect_uri="{}";"#, redirect_uri()));
+
+    let (client, events) = client(&transport);
+    assert_eq!(client.wait_for_login().unwrap(), redirect_uri());
+
+    match events.try_recv() {
+        Ok(Event::QrCode(image)) => assert_eq!(image, b"png".to_vec()),
+        e => panic!("expected QrCode, got {:?}", e),
+    }
+    match events.try_recv() {
+        Ok(Event::Scanned(avatar)) => assert_eq!(avatar, Some(b"avatar".to_vec())),
+        e => panic!("expected Scanned, got {:?}", e),
+    }
+    match events.try_recv() {
+        Ok(Event::Confirmed) => {}
+        e => panic!("expected Confirmed, got {:?}", e),
+    }
+
+    // tip=1 until scanned, then tip=0
+    let polls: Vec<String> = transport.requests()
+        .into_iter()
+        .filter(|r| r.url.contains("mmwebwx-bin/login?"))
+        .map(|r| r.url)
+        .collect();
+    assert_eq!(polls.len(), 3);
+    assert!(polls[0].contains("tip=1"));
+    assert!(polls[1].contains("tip=1"));
+    assert!(polls[2].contains("tip=0"));
+}
+
+#[test]
+fn expired_qr_code_is_refreshed() {
+    let transport = ScriptedTransport::new();
+    transport.respond("/jslogin", r#"window.QRLogin.uuid = "first";"#);
+    transport.respond("/jslogin", r#"window.QRLogin.uuid = "second";"#);
+    transport.respond("/qrcode/", "png");
+    transport.respond("uuid=first", "window.code=400;");
+    transport.respond("uuid=second",
+                      &format!(r#"window.code=200;window.redirect_uri="{}";"#, redirect_uri()));
+
+    let (client, _events) = client(&transport);
+    assert_eq!(client.wait_for_login().unwrap(), redirect_uri());
+}
+
+#[test]
+fn login_initializes_session() {
+    let transport = ScriptedTransport::new();
+    script_login(&transport);
+
+    let (client, events) = client(&transport);
+    client.login(&redirect_uri()).unwrap();
+
+    assert_eq!(client.user_name(), "@me");
+    assert_eq!(client.nick_name(), "me");
+
+    let session = client.session();
+    assert_eq!(session["Uin"], "1001");
+    assert_eq!(session["Sid"], "sid1");
+    assert_eq!(session["Skey"], "@crypt_1");
+    assert_eq!(session["PassTicket"], "pt1");
+    assert_eq!(session["Cookies"], json!(["webwx_data_ticket=dt"]));
+    assert_eq!(session["SyncKey"][1]["Val"], 200);
+
+    // `webwxinit` is authenticated with the credentials of the login page
+    let init = transport.requests().into_iter().find(|r| r.url.contains("webwxinit")).unwrap();
+    assert_eq!(init.method, "POST");
+    assert!(init.url.contains("pass_ticket=pt1"));
+    let body: Value = init.text().parse().unwrap();
+    assert_eq!(body["BaseRequest"]["Uin"], 1001);
+    assert_eq!(body["BaseRequest"]["Sid"], "sid1");
+
+    // groups of the init contact list are fetched in detail
+    match events.try_recv() {
+        Ok(Event::GroupAdded(chat)) => {
+            assert_eq!(chat.id(), "@@group");
+            assert_eq!(chat.members().len(), 1);
+        }
+        e => panic!("expected GroupAdded, got {:?}", e),
+    }
+}
+
+#[test]
+fn rejected_init_fails_login() {
+    let transport = ScriptedTransport::new();
+    transport.respond("webwxinit", r#"{"BaseResponse": {"Ret": 1100}}"#);
+    script_login(&transport);
+
+    let (client, _events) = client(&transport);
+    assert!(client.login(&redirect_uri()).is_err());
+}
+
+#[test]
+fn unreachable_server_keeps_stored_session() {
+    let transport = ScriptedTransport::new();
+
+    let (client, events) = client(&transport);
+    client.restore_session(&json!({ "Uin": "1001", "Sid": "sid1", "Skey": "@crypt_1" }));
+    client.run();
+
+    match events.try_recv() {
+        Ok(Event::LoginFailed(LoginError::Network(_))) => {}
+        e => panic!("expected a network LoginFailed, got {:?}", e),
+    }
+
+    // no qr-code is asked for while the session may still be good
+    assert!(!transport.requests().iter().any(|r| r.url.contains("/jslogin")));
+}
+
+#[test]
+fn sync_round_delivers_messages() {
+    let transport = ScriptedTransport::new();
+    script_login(&transport);
+    transport.respond("/synccheck", r#"window.synccheck={retcode:"0",selector:"2"}"#);
+
+    let sync = json!({
+        "BaseResponse": { "Ret": 0 },
+        "SyncKey": { "Count": 2, "List": [{ "Key": 1, "Val": 101 }, { "Key": 2, "Val": 201 }] },
+        "SyncCheckKey": { "Count": 1, "List": [{ "Key": 1, "Val": 102 }] },
+        "AddMsgList": [
+            { "MsgId": "m1", "MsgType": 1, "FromUserName": "@friend", "ToUserName": "@me",
+              "Content": "hello", "CreateTime": 1 },
+            { "MsgId": "m1", "MsgType": 1, "FromUserName": "@friend", "ToUserName": "@me",
+              "Content": "hello", "CreateTime": 1 },
+        ],
+    });
+    transport.respond("webwxsync?", &sync.to_string());
+
+    let (client, events) = client(&transport);
+    client.login(&redirect_uri()).unwrap();
+    while events.try_recv().is_ok() {}
+
+    assert_eq!(client.sync_check().unwrap(), SyncCheckResult::NewMessage);
+    let check = transport.requests().into_iter().find(|r| r.url.contains("/synccheck")).unwrap();
+    assert!(check.url.contains("synckey=1_100|2_200"));
+    assert!(check.url.contains("sid=sid1"));
+
+    client.sync().unwrap();
+
+    // the duplicate is dropped
+    match events.try_recv() {
+        Ok(Event::Message(msg)) => {
+            assert_eq!(msg.id, "m1");
+            assert_eq!(msg.from, "@friend");
+            assert_eq!(msg.msg_type, 1);
+            assert_eq!(msg.content, "hello");
+        }
+        e => panic!("expected Message, got {:?}", e),
+    }
+    match events.try_recv() {
+        Ok(Event::SessionUpdated(session)) => {
+            assert_eq!(session["SyncKey"][0]["Val"], 101);
+            assert_eq!(session["SyncCheckKey"][0]["Val"], 102);
+        }
+        e => panic!("expected SessionUpdated, got {:?}", e),
+    }
+    assert!(events.try_recv().is_err());
+
+    // `webwxsync` acknowledges the key of `webwxinit`
+    let sync = transport.requests().into_iter().find(|r| r.url.contains("webwxsync?")).unwrap();
+    let body: Value = sync.text().parse().unwrap();
+    assert_eq!(body["SyncKey"]["List"][0]["Val"], 100);
+
+    // the next `synccheck` continues from `SyncCheckKey`
+    client.sync_check().unwrap();
+    let check = transport.requests().into_iter().filter(|r| r.url.contains("/synccheck")).last();
+    assert!(check.unwrap().url.contains("synckey=1_102"));
+}
+
+#[test]
+fn rejected_sync_is_an_error() {
+    let transport = ScriptedTransport::new();
+    script_login(&transport);
+    transport.respond("webwxsync?", r#"{"BaseResponse": {"Ret": 1101}}"#);
+
+    let (client, events) = client(&transport);
+    client.login(&redirect_uri()).unwrap();
+    while events.try_recv().is_ok() {}
+
+    assert!(client.sync().is_err());
+    assert_eq!(client.session()["SyncKey"][0]["Val"], 100);
+    assert!(events.try_recv().is_err());
+}
+
+#[test]
+fn partial_contact_changes_are_applied() {
+    let transport = ScriptedTransport::new();
+    script_login(&transport);
+    let sync = json!({
+        "BaseResponse": { "Ret": 0 },
+        "ModContactList": [{ "UserName": "@new", "NickName": "new" }, { "UserName": "@@room" }],
+    });
+    transport.respond("webwxsync?", &sync.to_string());
+
+    let (client, events) = client(&transport);
+    client.login(&redirect_uri()).unwrap();
+    while events.try_recv().is_ok() {}
+
+    client.sync().unwrap();
+
+    match events.try_recv() {
+        Ok(Event::ContactAdded(user)) => assert_eq!(user.nick_name(), "new"),
+        e => panic!("expected ContactAdded, got {:?}", e),
+    }
+    match events.try_recv() {
+        Ok(Event::GroupAdded(chat)) => assert!(chat.members().is_empty()),
+        e => panic!("expected GroupAdded, got {:?}", e),
+    }
+}
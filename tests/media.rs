@@ -0,0 +1,96 @@
+extern crate wechat;
+#[macro_use]
+extern crate serde_json;
+
+mod support;
+
+use std::sync::mpsc::channel;
+use wechat::client::{Client, Error};
+use wechat::transport::{Endpoints, Response};
+use support::ScriptedTransport;
+
+/// the size of one ranged request.
+const PART: usize = 512 * 1024;
+
+fn client(transport: &ScriptedTransport) -> Client {
+    let (tx, _) = channel();
+
+    Client::new(Box::new(transport.clone()), Endpoints::with_base("http://stand-in"), tx)
+}
+
+fn part(status: u16, len: usize, byte: u8) -> Response {
+    Response {
+        status: status,
+        cookies: vec![],
+        body: vec![byte; len],
+    }
+}
+
+fn file_message() -> serde_json::Value {
+    json!({ "FromUserName": "@friend", "MediaId": "@media", "EncryFileName": "a.txt" })
+}
+
+#[test]
+fn media_is_fetched_in_parts() {
+    let transport = ScriptedTransport::new();
+    transport.respond_with("webwxgetmedia", part(206, PART, 1));
+    transport.respond_with("webwxgetmedia", part(206, 3, 2));
+
+    let mut out = Vec::new();
+    let mut progress = vec![];
+    let len = client(&transport)
+        .get_media(&file_message(), &mut out, |received| {
+            progress.push(received);
+            true
+        })
+        .unwrap();
+
+    assert_eq!(len, PART + 3);
+    assert_eq!(out.len(), PART + 3);
+    assert_eq!(out[PART - 1], 1);
+    assert_eq!(&out[PART..], &[2, 2, 2]);
+    assert_eq!(progress, vec![PART, PART + 3]);
+
+    let requests = transport.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].header("Range"), Some(format!("bytes=0-{}", PART - 1)));
+    assert_eq!(requests[1].header("Range"), Some(format!("bytes={}-{}", PART, 2 * PART - 1)));
+}
+
+#[test]
+fn media_without_range_support_is_fetched_at_once() {
+    let transport = ScriptedTransport::new();
+    transport.respond_with("webwxgetmedia", part(200, PART + 3, 1));
+
+    let mut out = Vec::new();
+    let len = client(&transport).get_media(&file_message(), &mut out, |_| true).unwrap();
+
+    assert_eq!(len, PART + 3);
+    assert_eq!(out.len(), PART + 3);
+    assert_eq!(transport.requests().len(), 1);
+}
+
+#[test]
+fn cancelled_media_download_stops() {
+    let transport = ScriptedTransport::new();
+    transport.respond_with("webwxgetmedia", part(206, PART, 1));
+
+    let mut out = Vec::new();
+    match client(&transport).get_media(&file_message(), &mut out, |_| false) {
+        Err(Error::Cancelled) => {}
+        r => panic!("expected Cancelled, got {:?}", r.map_err(|e| e.to_string())),
+    }
+    assert_eq!(transport.requests().len(), 1);
+}
+
+#[test]
+fn video_is_fetched_in_parts() {
+    let transport = ScriptedTransport::new();
+    transport.respond_with("webwxgetvideo", part(206, PART, 1));
+    transport.respond_with("webwxgetvideo", part(416, 0, 0));
+
+    let mut out = Vec::new();
+    assert_eq!(client(&transport).get_video("1", &mut out).unwrap(), PART);
+    assert_eq!(out.len(), PART);
+    assert_eq!(transport.requests().len(), 2);
+}
@@ -0,0 +1,120 @@
+//! code shared by the integration tests, each test file only uses part of it.
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use wechat::transport::{Error, RawHeaders, Response, Result, Transport};
+
+/// A `Transport` answering from a script instead of the network, to test
+/// the protocol code against a stand-in server. clones share the script.
+#[derive(Clone, Default)]
+pub struct ScriptedTransport {
+    script: Arc<Mutex<Script>>,
+}
+
+#[derive(Default)]
+struct Script {
+    /// url fragment and the responses still to give for it.
+    routes: Vec<(String, VecDeque<Response>)>,
+    requests: Vec<Request>,
+}
+
+/// A request seen by a `ScriptedTransport`.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: &'static str,
+    pub url: String,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    /// value of the header `name`, if it was sent.
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.headers
+            .iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| String::from_utf8_lossy(value).into_owned())
+    }
+}
+
+impl ScriptedTransport {
+    pub fn new() -> ScriptedTransport {
+        ScriptedTransport::default()
+    }
+
+    /// answer requests whose url contains `fragment` with `body`.
+    pub fn respond(&self, fragment: &str, body: &str) {
+        self.respond_with(fragment,
+                          Response {
+                              status: 200,
+                              cookies: vec![],
+                              body: body.as_bytes().to_vec(),
+                          });
+    }
+
+    /// queue `response` for `fragment`. queued responses are given in order
+    /// and the last one is repeated, the first fragment added that matches a
+    /// url wins.
+    pub fn respond_with(&self, fragment: &str, response: Response) {
+        let mut script = self.script.lock().unwrap();
+
+        if let Some(&mut (_, ref mut responses)) =
+            script.routes.iter_mut().find(|&&mut (ref f, _)| f == fragment) {
+            responses.push_back(response);
+            return;
+        }
+
+        let mut responses = VecDeque::new();
+        responses.push_back(response);
+        script.routes.push((fragment.to_owned(), responses));
+    }
+
+    /// every request made so far, oldest first.
+    pub fn requests(&self) -> Vec<Request> {
+        self.script.lock().unwrap().requests.clone()
+    }
+
+    fn answer(&self,
+              method: &'static str,
+              url: &str,
+              headers: &RawHeaders,
+              body: &[u8])
+              -> Result<Response> {
+        let mut script = self.script.lock().unwrap();
+        let Script { ref mut routes, ref mut requests } = *script;
+
+        requests.push(Request {
+                          method: method,
+                          url: url.to_owned(),
+                          headers: headers.to_vec(),
+                          body: body.to_vec(),
+                      });
+
+        match routes.iter_mut().find(|&&mut (ref f, _)| url.contains(f.as_str())) {
+            Some(&mut (_, ref mut responses)) if responses.len() > 1 => {
+                Ok(responses.pop_front().unwrap())
+            }
+            Some(&mut (_, ref responses)) => Ok(responses[0].clone()),
+            None => {
+                let message = format!("no response scripted for {}", url);
+                Err(Error::Io(io::Error::new(io::ErrorKind::NotFound, message)))
+            }
+        }
+    }
+}
+
+impl Transport for ScriptedTransport {
+    fn get(&self, url: &str, headers: &RawHeaders) -> Result<Response> {
+        self.answer("GET", url, headers, &[])
+    }
+
+    fn post(&self, url: &str, headers: &RawHeaders, body: &[u8]) -> Result<Response> {
+        self.answer("POST", url, headers, body)
+    }
+}